@@ -1,69 +1,162 @@
-#[derive(Debug)]
+use crate::diag::Span;
+
+#[derive(Debug, Clone)]
 pub struct CompUnit {
     pub list: Vec<CompItem>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum CompItem {
     FuncDef(FuncDef),
+    FuncDecl(FuncDecl),
     ConstDecl(Vec<ConstDef>),
     VarDecl(Vec<VarDef>),
+    ExternDecl(Vec<VarDef>),
+}
+
+impl CompItem {
+    /// The names the item declares.
+    pub fn names(&self) -> Vec<String> {
+        match self {
+            CompItem::FuncDef(FuncDef { ident, .. })
+            | CompItem::FuncDecl(FuncDecl { ident, .. }) => {
+                vec![ident.clone()]
+            }
+            CompItem::ConstDecl(defs) => defs.iter().map(|def| def.id.clone()).collect(),
+            CompItem::VarDecl(defs) | CompItem::ExternDecl(defs) => {
+                defs.iter().map(|def| def.id.clone()).collect()
+            }
+        }
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct FuncDef {
     pub func_type: FuncType,
     pub ident: String,
     pub block: Block,
     pub params: Vec<FuncParam>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub struct FuncDecl {
+    pub func_type: FuncType,
+    pub ident: String,
+    pub params: Vec<FuncParam>,
+    pub span: Span,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct FuncParam {
     pub ident: String,
     pub kind: String,
+    pub span: Span,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum FuncType {
     Int,
     Void,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Block {
     pub items: Vec<BlockItem>,
 }
 
-#[derive(Debug)]
+impl Block {
+    /// The functions called in the block, in order of appearance.
+    pub fn callees(&self) -> Vec<&str> {
+        let mut out = Vec::new();
+        self.calls(&mut out);
+        out
+    }
+
+    fn calls<'a>(&'a self, out: &mut Vec<&'a str>) {
+        for item in self.items.iter() {
+            match item {
+                BlockItem::ConstDecl(defs) => defs.iter().for_each(|def| def.value.calls(out)),
+                BlockItem::VarDecl(defs) => defs
+                    .iter()
+                    .filter_map(|def| def.value.as_ref())
+                    .for_each(|value| value.calls(out)),
+                BlockItem::Stmt(stmt, _) => stmt.calls(out),
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub enum BlockItem {
     ConstDecl(Vec<ConstDef>),
     VarDecl(Vec<VarDef>),
-    Stmt(Stmt),
+    Stmt(Stmt, Span),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Stmt {
     Ret(Option<Expr>),
-    Assign(String, Expr),
+    Assign(String, Expr, Span),
     Block(Box<Block>),
     Expr(Option<Expr>),
-    IfElse(Expr, Box<Stmt>, Option<Box<Stmt>>),
-    While(Expr, Box<Stmt>),
-    Break,
-    Continue,
+    /// The branches and the loop body carry their spans, like the
+    /// statements in a block.
+    IfElse(Expr, Box<(Stmt, Span)>, Option<Box<(Stmt, Span)>>),
+    While(Expr, Box<(Stmt, Span)>),
+    Break(Span),
+    Continue(Span),
 }
 
-#[derive(Debug)]
+impl Stmt {
+    fn calls<'a>(&'a self, out: &mut Vec<&'a str>) {
+        match self {
+            Stmt::Ret(Some(e)) | Stmt::Assign(_, e, _) | Stmt::Expr(Some(e)) => e.calls(out),
+            Stmt::Ret(None) | Stmt::Expr(None) | Stmt::Break(_) | Stmt::Continue(_) => {}
+            Stmt::Block(block) => block.calls(out),
+            Stmt::IfElse(cond, if_then, else_then) => {
+                cond.calls(out);
+                if_then.0.calls(out);
+                if let Some(else_then) = else_then {
+                    else_then.0.calls(out);
+                }
+            }
+            Stmt::While(cond, body) => {
+                cond.calls(out);
+                body.0.calls(out);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+#[allow(clippy::enum_variant_names)]
 pub enum Expr {
     Number(i32),
     UnaryExpr(UnaryOp, Box<Expr>),
-    BinaryExpr(Box<Expr>, BinaryOp, Box<Expr>),
-    LVal(String),
-    Func(String, Vec<Expr>),
+    BinaryExpr(Box<Expr>, BinaryOp, Box<Expr>, Span),
+    LVal(String, Span),
+    Func(String, Vec<Expr>, Span),
+}
+
+impl Expr {
+    fn calls<'a>(&'a self, out: &mut Vec<&'a str>) {
+        match self {
+            Expr::Number(_) | Expr::LVal(..) => {}
+            Expr::UnaryExpr(_, e) => e.calls(out),
+            Expr::BinaryExpr(lhs, _, rhs, _) => {
+                lhs.calls(out);
+                rhs.calls(out);
+            }
+            Expr::Func(ident, args, _) => {
+                args.iter().for_each(|arg| arg.calls(out));
+                out.push(ident);
+            }
+        }
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum BinaryOp {
     Plus,
     Minus,
@@ -80,23 +173,25 @@ pub enum BinaryOp {
     Or,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum UnaryOp {
     Plus,
     Minus,
     Not,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ConstDef {
     pub kind: String,
     pub id: String,
     pub value: Expr,
+    pub span: Span,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct VarDef {
     pub kind: String,
     pub id: String,
     pub value: Option<Expr>,
+    pub span: Span,
 }
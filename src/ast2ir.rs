@@ -1,19 +1,28 @@
 use crate::ast::*;
+use crate::diag::{self, Diagnostic, SourceMap, Span};
+use crate::runtime::{Runtime, TIMING_FUNCS};
 use lazy_static::lazy_static;
-use std::{collections::HashMap, sync::Mutex};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+};
 lazy_static! {
     static ref COUNTER: Mutex<i32> = Mutex::new(-1);
     static ref BLOCK_COUNTER: Mutex<i32> = Mutex::new(-1);
     static ref IF_COUNTER: Mutex<i32> = Mutex::new(-1);
     static ref WHILE_COUNTER: Mutex<i32> = Mutex::new(-1);
 }
-enum IdElement {
+pub(crate) enum IdElement {
     Const(i32),
+    #[allow(dead_code)]
     Var(String),
-    Func(String),
+    /// The return kind, `i32` or `void`, and the number of parameters.
+    Func(String, usize),
 }
-struct IdTable<'a> {
+pub(crate) struct IdTable<'a> {
     table: HashMap<String, IdElement>,
+    /// Where the constants and variables of this scope are defined.
+    spans: HashMap<String, Span>,
     father: Option<&'a IdTable<'a>>,
     offset: i32,
 }
@@ -21,8 +30,9 @@ impl<'a> IdTable<'a> {
     pub fn new(father: Option<&'a IdTable<'a>>, offset: i32) -> Self {
         IdTable {
             table: HashMap::new(),
-            father: father,
-            offset: offset,
+            spans: HashMap::new(),
+            father,
+            offset,
         }
     }
     pub fn insert(&mut self, k: String, v: IdElement) -> bool {
@@ -30,6 +40,22 @@ impl<'a> IdTable<'a> {
             .insert(format!("{}_{}", k, self.offset), v)
             .is_none()
     }
+    /// Adds a constant or variable defined at `span`. A second definition
+    /// in the same scope is an error and keeps the first.
+    pub fn define(&mut self, k: String, v: IdElement, span: Span) -> bool {
+        let key = format!("{}_{}", k, self.offset);
+        if let Some(prev) = self.spans.get(&key) {
+            diag::emit(
+                Diagnostic::error(format!("redefinition of `{}`", k), Some(span))
+                    .with_code("E0005")
+                    .with_related(*prev, format!("`{}` is first defined here", k)),
+            );
+            return false;
+        }
+        self.spans.insert(key.clone(), span);
+        self.table.insert(key, v);
+        true
+    }
     pub fn get(&self, k: &String) -> (Option<&IdElement>, String) {
         if self.table.contains_key(&format!("{}_{}", k, self.offset)) {
             (
@@ -42,104 +68,633 @@ impl<'a> IdTable<'a> {
             (None, String::new())
         }
     }
+    /// Every name visible from this scope, innermost scope first.
+    pub fn names(&self) -> Vec<(&str, &IdElement)> {
+        let suffix = format!("_{}", self.offset);
+        let mut out: Vec<(&str, &IdElement)> = self
+            .table
+            .iter()
+            .filter_map(|(k, v)| Some((k.strip_suffix(&suffix)?, v)))
+            .collect();
+        if let Some(father) = self.father {
+            out.extend(father.names());
+        }
+        out
+    }
 }
-pub fn ast2ir(ast: &mut CompUnit) -> String {
-    let mut out = String::new();
+/// A Koopa program the back end can translate on its own. Koopa text has no
+/// forward declarations: a function can only be called after its `fun` or
+/// `decl`, and a `decl` may not be followed by a definition. The functions
+/// of a file are emitted callees first, so only recursion through several
+/// functions starts a new module; the modules are linked at the assembly
+/// level.
+pub struct Module {
+    pub koopa: String,
+    /// Globals that only stand in for a definition in another module. The
+    /// back end must not allocate them.
+    pub externs: HashSet<String>,
+    /// The assembly symbol of each global, its name in the source, by its
+    /// mangled Koopa name.
+    pub symbols: HashMap<String, String>,
+}
+
+enum Linkage {
+    Func {
+        kind: String,
+        params: usize,
+        defined: bool,
+    },
+    Var {
+        defined: bool,
+        span: Span,
+    },
+}
+
+pub fn ast2ir(units: &mut [CompUnit], runtime: &Runtime, source_map: &SourceMap) -> Vec<Module> {
+    lower(units, runtime, source_map, true)
+}
+
+/// Like `ast2ir`, for files that may be only part of a program, such as
+/// one open in an editor. What other files could define is not checked:
+/// that there is a `main` and that every `extern` variable is defined.
+pub fn ast2ir_part(
+    units: &mut [CompUnit],
+    runtime: &Runtime,
+    source_map: &SourceMap,
+) -> Vec<Module> {
+    lower(units, runtime, source_map, false)
+}
+
+fn lower(
+    units: &mut [CompUnit],
+    runtime: &Runtime,
+    source_map: &SourceMap,
+    whole_program: bool,
+) -> Vec<Module> {
     let block_id = {
         let mut counter_guard = BLOCK_COUNTER.lock().unwrap();
         *counter_guard += 1;
         *counter_guard
     };
-    let mut global_id_table = IdTable::new(None, block_id); // Global scope
-    out += "decl @getint(): i32\ndecl @getch(): i32\ndecl @getarray(*i32): i32\ndecl @putint(i32)\ndecl @putch(i32)\ndecl @putarray(i32, *i32)\ndecl @starttime()\ndecl @stoptime()\n";
-    global_id_table.insert("getint".to_string(), IdElement::Func("i32".to_string()));
-    global_id_table.insert("getch".to_string(), IdElement::Func("i32".to_string()));
-    global_id_table.insert("getarray".to_string(), IdElement::Func("i32".to_string()));
-    global_id_table.insert("putint".to_string(), IdElement::Func("void".to_string()));
-    global_id_table.insert("putch".to_string(), IdElement::Func("void".to_string()));
-    global_id_table.insert("putarray".to_string(), IdElement::Func("void".to_string()));
-    global_id_table.insert("starttime".to_string(), IdElement::Func("void".to_string()));
-    global_id_table.insert("stoptime".to_string(), IdElement::Func("void".to_string()));
-    for comp_item in ast.list.iter_mut() {
-        match comp_item {
-            CompItem::FuncDef(func_def) => {
-                let func_type = match func_def.func_type {
-                    FuncType::Int => ": i32",
-                    FuncType::Void => "",
-                };
-                global_id_table.insert(
-                    func_def.ident.clone(),
-                    IdElement::Func(match func_def.func_type {
+    let mut linkage: HashMap<String, Linkage> = HashMap::new();
+    for func in runtime.funcs() {
+        linkage.insert(
+            func.name.clone(),
+            Linkage::Func {
+                kind: func.kind.clone(),
+                params: func.params.len(),
+                defined: true,
+            },
+        );
+    }
+    // A program may define a function of its own named `starttime`, in any
+    // of its files.
+    let declared: HashSet<String> = units
+        .iter()
+        .flat_map(|ast| ast.list.iter().flat_map(CompItem::names))
+        .collect();
+    let mut modules = Vec::new();
+    for ast in units.iter_mut() {
+        lower_timing_calls(ast, &declared, source_map);
+        // Every file gets its own global scope, but all of them share the
+        // same offset so that a global is mangled to the same name in every
+        // file declaring it.
+        let mut global_id_table = IdTable::new(None, block_id); // Global scope
+        for func in runtime.funcs() {
+            global_id_table.insert(
+                func.name.clone(),
+                IdElement::Func(func.kind.clone(), func.params.len()),
+            );
+        }
+        let mut globals = String::new();
+        let mut defined_globals = Vec::new();
+        let mut extern_globals = Vec::new();
+        let mut symbols = HashMap::new();
+        let mut funcs = Vec::new();
+        for comp_item in ast.list.iter_mut() {
+            match comp_item {
+                CompItem::FuncDecl(func_decl) => {
+                    let kind = match func_decl.func_type {
                         FuncType::Int => "i32".to_string(),
                         FuncType::Void => "void".to_string(),
-                    }),
-                );
-                out += &format!("fun @{}(", &func_def.ident);
-                for i in 0..func_def.params.len() {
-                    let param = &func_def.params[i];
-                    out += &format!("@{}: {}", param.ident, param.kind);
-                    if i < func_def.params.len() - 1 {
-                        out += ", ";
+                    };
+                    declare_func(
+                        &mut linkage,
+                        &func_decl.ident,
+                        &kind,
+                        func_decl.params.len(),
+                        false,
+                        func_decl.span,
+                    );
+                    global_id_table.insert(
+                        func_decl.ident.clone(),
+                        IdElement::Func(kind, func_decl.params.len()),
+                    );
+                }
+                CompItem::FuncDef(func_def) => {
+                    let mut out = String::new();
+                    let func_type = match func_def.func_type {
+                        FuncType::Int => ": i32",
+                        FuncType::Void => "",
+                    };
+                    let kind = match func_def.func_type {
+                        FuncType::Int => "i32".to_string(),
+                        FuncType::Void => "void".to_string(),
+                    };
+                    declare_func(
+                        &mut linkage,
+                        &func_def.ident,
+                        &kind,
+                        func_def.params.len(),
+                        true,
+                        func_def.span,
+                    );
+                    global_id_table.insert(
+                        func_def.ident.clone(),
+                        IdElement::Func(kind, func_def.params.len()),
+                    );
+                    out += &format!("fun @{}(", &func_def.ident);
+                    for i in 0..func_def.params.len() {
+                        let param = &func_def.params[i];
+                        out += &format!("@{}: {}", param.ident, param.kind);
+                        if i < func_def.params.len() - 1 {
+                            out += ", ";
+                        }
                     }
+                    out += &format!("){} {{\n", func_type);
+                    out += "%entry:\n";
+                    let id = {
+                        let mut counter_guard = BLOCK_COUNTER.lock().unwrap();
+                        *counter_guard += 1;
+                        *counter_guard
+                    };
+                    let mut table = IdTable::new(Some(&global_id_table), id);
+                    // Parameters share the outermost scope of the body.
+                    for param in func_def.params.iter() {
+                        let var = IdElement::Var(param.kind.clone());
+                        if !table.define(param.ident.clone(), var, param.span) {
+                            continue;
+                        }
+                        out += &format!("@{} = alloc i32\n", table.get(&param.ident).1);
+                        out += &format!("store @{}, @{}\n", param.ident, table.get(&param.ident).1);
+                    }
+                    let (st, has_ret) = &block2ir(&mut func_def.block, &mut table, -1);
+                    out += st;
+                    // Falling off the end is reported by the lint pass; the
+                    // block still needs a terminator, and `main` returns 0.
+                    if !*has_ret {
+                        out += match func_def.func_type {
+                            FuncType::Int => "ret 0\n",
+                            FuncType::Void => "ret\n",
+                        };
+                    }
+                    out += "}\n";
+                    let callees = func_def.block.callees();
+                    funcs.push(Func {
+                        name: func_def.ident.clone(),
+                        callees: callees.into_iter().map(str::to_string).collect(),
+                        koopa: out,
+                    });
                 }
-                out += &format!("){} {{\n", func_type);
-                out += "%entry:\n";
-                let id = {
-                    let mut counter_guard = BLOCK_COUNTER.lock().unwrap();
-                    *counter_guard += 1;
-                    *counter_guard
-                };
-                let mut table = IdTable::new(Some(&global_id_table), id);
-                for param in func_def.params.iter() {
-                    table.insert(param.ident.clone(), IdElement::Var(param.kind.clone()));
-                    out += &format!("@{} = alloc i32\n", table.get(&param.ident).1);
-                    out += &format!("store @{}, @{}\n", param.ident, table.get(&param.ident).1);
+                CompItem::ConstDecl(const_defs) => {
+                    for const_def in const_defs.iter() {
+                        let value = const_init(const_def, &global_id_table);
+                        global_id_table.define(
+                            const_def.id.clone(),
+                            IdElement::Const(value),
+                            const_def.span,
+                        );
+                    }
                 }
-                let (st, has_ret) = &block2ir(&mut func_def.block, &mut table, -1);
-                if !*has_ret {
-                    out += "ret\n";
+
+                CompItem::VarDecl(var_defs) => {
+                    for var_def in var_defs.iter() {
+                        let var = IdElement::Var("i32".to_string());
+                        if !define_var(&mut linkage, &var_def.id, var_def.span)
+                            || !global_id_table.define(var_def.id.clone(), var, var_def.span)
+                        {
+                            continue;
+                        }
+                        let name = global_id_table.get(&var_def.id).1;
+                        match &var_def.value {
+                            None => {
+                                globals += &format!("global @{} = alloc i32, zeroinit\n", name);
+                            }
+                            Some(e) => {
+                                // Global initializers are evaluated by the
+                                // compiler; there is no code to run them.
+                                let value = compute_expr(e, &global_id_table).unwrap_or_else(|err| {
+                                    diag::emit(err.with_note(format!(
+                                        "global variable `{}` can only be initialized with a constant expression",
+                                        var_def.id
+                                    )));
+                                    0
+                                });
+                                globals += &format!("global @{} = alloc i32, {}\n", name, value);
+                            }
+                        }
+                        symbols.insert(name.clone(), var_def.id.clone());
+                        defined_globals.push(name);
+                    }
+                }
+                CompItem::ExternDecl(var_defs) => {
+                    for var_def in var_defs.iter() {
+                        match linkage.get(&var_def.id) {
+                            Some(Linkage::Func { .. }) => redeclared(&var_def.id, var_def.span),
+                            Some(Linkage::Var { .. }) => {}
+                            None => {
+                                linkage.insert(
+                                    var_def.id.clone(),
+                                    Linkage::Var {
+                                        defined: false,
+                                        span: var_def.span,
+                                    },
+                                );
+                            }
+                        }
+                        global_id_table
+                            .insert(var_def.id.clone(), IdElement::Var("i32".to_string()));
+                        let name = global_id_table.get(&var_def.id).1;
+                        symbols.insert(name.clone(), var_def.id.clone());
+                        extern_globals.push(name);
+                    }
                 }
-                out += st;
-                out += "}\n";
             }
-            CompItem::ConstDecl(const_defs) => {
-                for const_def in const_defs.iter() {
-                    let value = compute_expr(&const_def.value, &global_id_table);
-                    global_id_table.insert(const_def.id.clone(), IdElement::Const(value));
+        }
+        extern_globals.retain(|name| !defined_globals.contains(name));
+        split_modules(
+            &mut modules,
+            runtime,
+            globals,
+            &defined_globals,
+            &extern_globals,
+            &symbols,
+            call_order(funcs),
+            &linkage,
+        );
+    }
+    if !whole_program {
+        check_main(units);
+        return modules;
+    }
+    let mut undefined: Vec<(&String, &Span)> = linkage
+        .iter()
+        .filter_map(|(name, item)| match item {
+            Linkage::Var {
+                defined: false,
+                span,
+            } => Some((name, span)),
+            _ => None,
+        })
+        .collect();
+    undefined.sort_by_key(|(_, span)| span.lo);
+    for (name, span) in undefined {
+        diag::emit(
+            Diagnostic::error(format!("undefined reference to `{}`", name), Some(*span))
+                .with_code("E0007")
+                .with_note("the variable is declared `extern` but no file defines it"),
+        );
+    }
+    if !check_main(units) {
+        diag::emit(
+            Diagnostic::error("no `main` function in the program", None)
+                .with_code("E0010")
+                .with_note("every SysY program must define `int main() { ... }`"),
+        );
+    }
+    modules
+}
+/// Checks that `main` is only ever declared as the program entry
+/// `int main()`, and tells whether it is defined. Every declaration of
+/// `main` is checked, so a wrong one is reported where it is written rather
+/// than at link time.
+fn check_main(units: &[CompUnit]) -> bool {
+    let mut defined = false;
+    for ast in units.iter() {
+        for item in ast.list.iter() {
+            let (func_type, params, span) = match item {
+                CompItem::FuncDef(FuncDef {
+                    func_type,
+                    ident,
+                    params,
+                    span,
+                    ..
+                }) if ident == "main" => {
+                    defined = true;
+                    (func_type, params, span)
                 }
+                CompItem::FuncDecl(FuncDecl {
+                    func_type,
+                    ident,
+                    params,
+                    span,
+                }) if ident == "main" => (func_type, params, span),
+                CompItem::VarDecl(defs) | CompItem::ExternDecl(defs) => {
+                    for def in defs.iter().filter(|def| def.id == "main") {
+                        diag::emit(
+                            Diagnostic::error("`main` must be a function", Some(def.span))
+                                .with_code("E0010")
+                                .with_note("`main` is the entry point: `int main() { ... }`"),
+                        );
+                    }
+                    continue;
+                }
+                _ => continue,
+            };
+            if let FuncType::Void = func_type {
+                diag::emit(
+                    Diagnostic::error("`main` must return `int`", Some(*span))
+                        .with_code("E0010")
+                        .with_note("the return value is the exit code of the program"),
+                );
             }
-
-            CompItem::VarDecl(var_defs) => {
-                for var_def in var_defs.iter() {
-                    global_id_table.insert(var_def.id.clone(), IdElement::Var("i32".to_string()));
-                    match &var_def.value {
-                        None => {
-                            out += &format!(
-                                "global @{} = alloc i32, zeroinit\n",
-                                global_id_table.get(&var_def.id).1
-                            );
-                        }
-                        Some(e) => {
-                            let (st, pos) = expr2ir(e, &global_id_table);
-                            let pos = if st == String::new() {
-                                pos.to_string()
-                            } else {
-                                format!("t{}", pos)
-                            };
-                            out += &st;
-                            out += &format!(
-                                "global @{} = alloc i32, {}\n",
-                                global_id_table.get(&var_def.id).1,
-                                pos
-                            );
+            if let (Some(first), Some(last)) = (params.first(), params.last()) {
+                diag::emit(
+                    Diagnostic::error(
+                        "`main` must not take parameters",
+                        Some(Span::new(first.span.lo, last.span.hi)),
+                    )
+                    .with_code("E0010")
+                    .with_note("declare it as `int main()`"),
+                );
+            }
+        }
+    }
+    defined
+}
+/// Rewrites `starttime()` and `stoptime()` to the runtime's
+/// `_sysy_starttime(line)` and `_sysy_stoptime(line)`, the way the macros in
+/// `sylib.h` do, unless the program `declared` a name of its own like them.
+fn lower_timing_calls(ast: &mut CompUnit, declared: &HashSet<String>, source_map: &SourceMap) {
+    let lowered: Vec<(&str, &str)> = TIMING_FUNCS
+        .into_iter()
+        .filter(|(name, _)| !declared.contains(*name))
+        .collect();
+    fn lower_expr(expr: &mut Expr, funcs: &[(&str, &str)], source_map: &SourceMap) {
+        match expr {
+            Expr::Number(_) | Expr::LVal(..) => {}
+            Expr::UnaryExpr(_, e) => lower_expr(e, funcs, source_map),
+            Expr::BinaryExpr(lhs, _, rhs, _) => {
+                lower_expr(lhs, funcs, source_map);
+                lower_expr(rhs, funcs, source_map);
+            }
+            Expr::Func(ident, args, span) => {
+                for arg in args.iter_mut() {
+                    lower_expr(arg, funcs, source_map);
+                }
+                let lowered = funcs.iter().find(|(name, _)| name == ident);
+                if let (Some((_, lowered)), true) = (lowered, args.is_empty()) {
+                    let line = source_map.lookup(span.lo).line;
+                    *ident = lowered.to_string();
+                    args.push(Expr::Number(line as i32));
+                }
+            }
+        }
+    }
+    fn lower_block(block: &mut Block, funcs: &[(&str, &str)], source_map: &SourceMap) {
+        for item in block.items.iter_mut() {
+            match item {
+                BlockItem::ConstDecl(defs) => {
+                    for def in defs.iter_mut() {
+                        lower_expr(&mut def.value, funcs, source_map);
+                    }
+                }
+                BlockItem::VarDecl(defs) => {
+                    for def in defs.iter_mut() {
+                        if let Some(value) = &mut def.value {
+                            lower_expr(value, funcs, source_map);
                         }
                     }
                 }
+                BlockItem::Stmt(stmt, _) => lower_stmt(stmt, funcs, source_map),
+            }
+        }
+    }
+    fn lower_stmt(stmt: &mut Stmt, funcs: &[(&str, &str)], source_map: &SourceMap) {
+        match stmt {
+            Stmt::Ret(Some(e)) | Stmt::Assign(_, e, _) | Stmt::Expr(Some(e)) => {
+                lower_expr(e, funcs, source_map)
+            }
+            Stmt::Ret(None) | Stmt::Expr(None) | Stmt::Break(_) | Stmt::Continue(_) => {}
+            Stmt::Block(block) => lower_block(block, funcs, source_map),
+            Stmt::IfElse(cond, if_then, else_then) => {
+                lower_expr(cond, funcs, source_map);
+                lower_stmt(&mut if_then.0, funcs, source_map);
+                if let Some(else_then) = else_then {
+                    lower_stmt(&mut else_then.0, funcs, source_map);
+                }
+            }
+            Stmt::While(cond, body) => {
+                lower_expr(cond, funcs, source_map);
+                lower_stmt(&mut body.0, funcs, source_map);
+            }
+        }
+    }
+    for item in ast.list.iter_mut() {
+        if let CompItem::FuncDef(func_def) = item {
+            lower_block(&mut func_def.block, &lowered, source_map);
+        }
+    }
+}
+fn redeclared(name: &str, span: Span) {
+    diag::emit(
+        Diagnostic::error(
+            format!("`{}` redeclared as a different kind of symbol", name),
+            Some(span),
+        )
+        .with_code("E0005"),
+    );
+}
+fn declare_func(
+    linkage: &mut HashMap<String, Linkage>,
+    name: &str,
+    kind: &str,
+    params: usize,
+    define: bool,
+    span: Span,
+) {
+    match linkage.get_mut(name) {
+        Some(Linkage::Func {
+            kind: prev_kind,
+            params: prev_params,
+            defined,
+        }) => {
+            if prev_kind != kind || *prev_params != params {
+                diag::emit(
+                    Diagnostic::error(format!("conflicting types for `{}`", name), Some(span))
+                        .with_code("E0006")
+                        .with_note(format!(
+                            "previously declared returning {} with {} parameter(s)",
+                            prev_kind, prev_params
+                        )),
+                );
+            } else if define && *defined {
+                diag::emit(
+                    Diagnostic::error(format!("redefinition of function `{}`", name), Some(span))
+                        .with_code("E0005"),
+                );
+            }
+            *defined |= define;
+        }
+        Some(Linkage::Var { .. }) => redeclared(name, span),
+        None => {
+            linkage.insert(
+                name.to_string(),
+                Linkage::Func {
+                    kind: kind.to_string(),
+                    params,
+                    defined: define,
+                },
+            );
+        }
+    }
+}
+/// Records the definition of a global variable. Returns whether it is the
+/// first one.
+fn define_var(linkage: &mut HashMap<String, Linkage>, name: &str, span: Span) -> bool {
+    match linkage.get_mut(name) {
+        Some(Linkage::Var { defined: true, .. }) => {
+            diag::emit(
+                Diagnostic::error(
+                    format!("redefinition of global variable `{}`", name),
+                    Some(span),
+                )
+                .with_code("E0005"),
+            );
+            return false;
+        }
+        Some(Linkage::Var { defined, .. }) => *defined = true,
+        Some(Linkage::Func { .. }) => {
+            redeclared(name, span);
+            return false;
+        }
+        None => {
+            linkage.insert(
+                name.to_string(),
+                Linkage::Var {
+                    defined: true,
+                    span,
+                },
+            );
+        }
+    }
+    true
+}
+/// A function definition lowered to Koopa.
+struct Func {
+    name: String,
+    /// The functions its body calls, from the syntax tree.
+    callees: Vec<String>,
+    koopa: String,
+}
+/// Orders the functions of one file so that each comes after the functions
+/// it calls, except where they call each other in a cycle. Otherwise they
+/// keep the order they are written in.
+fn call_order(funcs: Vec<Func>) -> Vec<Func> {
+    fn visit(
+        i: usize,
+        funcs: &[Func],
+        index: &HashMap<&str, usize>,
+        visited: &mut [bool],
+        order: &mut Vec<usize>,
+    ) {
+        if visited[i] {
+            return;
+        }
+        visited[i] = true;
+        for callee in funcs[i].callees.iter() {
+            if let Some(&j) = index.get(callee.as_str()) {
+                visit(j, funcs, index, visited, order);
+            }
+        }
+        order.push(i);
+    }
+    let mut index = HashMap::new();
+    for (i, func) in funcs.iter().enumerate() {
+        index.entry(func.name.as_str()).or_insert(i);
+    }
+    let mut visited = vec![false; funcs.len()];
+    let mut order = Vec::new();
+    for i in 0..funcs.len() {
+        visit(i, &funcs, &index, &mut visited, &mut order);
+    }
+    let mut funcs: Vec<Option<Func>> = funcs.into_iter().map(Some).collect();
+    order
+        .into_iter()
+        .map(|i| funcs[i].take().unwrap())
+        .collect()
+}
+/// Distributes the functions of one file, in call order, over as few
+/// modules as possible: a new one starts only at a function that was
+/// declared for a call before its definition. The first module defines the
+/// file's globals; later ones only refer to them.
+#[allow(clippy::too_many_arguments)]
+fn split_modules(
+    modules: &mut Vec<Module>,
+    runtime: &Runtime,
+    globals: String,
+    defined_globals: &[String],
+    extern_globals: &[String],
+    symbols: &HashMap<String, String>,
+    funcs: Vec<Func>,
+    linkage: &HashMap<String, Linkage>,
+) {
+    let mut groups: Vec<(Vec<String>, String)> = Vec::new();
+    let mut defined: HashSet<String> = HashSet::new();
+    let mut declared: Vec<String> = Vec::new();
+    let mut body = String::new();
+    for Func {
+        name,
+        callees,
+        koopa,
+    } in funcs
+    {
+        if declared.contains(&name) {
+            groups.push((std::mem::take(&mut declared), std::mem::take(&mut body)));
+            defined.clear();
+        }
+        defined.insert(name.clone());
+        for callee in callees {
+            if !defined.contains(&callee)
+                && !declared.contains(&callee)
+                && linkage.contains_key(&callee)
+                && runtime.get(&callee).is_none()
+            {
+                declared.push(callee);
+            }
+        }
+        body += &koopa;
+    }
+    groups.push((declared, body));
+    for (i, (declared, body)) in groups.into_iter().enumerate() {
+        let mut koopa = runtime.decls();
+        for name in declared.iter() {
+            if let Some(Linkage::Func { kind, params, .. }) = linkage.get(name) {
+                let params = vec!["i32"; *params].join(", ");
+                match kind.as_str() {
+                    "void" => koopa += &format!("decl @{}({})\n", name, params),
+                    _ => koopa += &format!("decl @{}({}): {}\n", name, params, kind),
+                }
             }
-            _ => todo!(),
         }
+        let mut externs: HashSet<String> = extern_globals.iter().cloned().collect();
+        if i == 0 {
+            koopa += &globals;
+        } else {
+            externs.extend(defined_globals.iter().cloned());
+        }
+        let mut stand_ins: Vec<&String> = externs.iter().collect();
+        stand_ins.sort();
+        for name in stand_ins {
+            koopa += &format!("global @{} = alloc i32, zeroinit\n", name);
+        }
+        koopa += &body;
+        modules.push(Module {
+            koopa,
+            externs,
+            symbols: symbols.clone(),
+        });
     }
-    out
 }
 fn stmt2ir(stmt: &mut Stmt, id_table: &mut IdTable, cur_while_id: i32) -> (String, bool) {
     let mut out = String::new();
@@ -162,14 +717,25 @@ fn stmt2ir(stmt: &mut Stmt, id_table: &mut IdTable, cur_while_id: i32) -> (Strin
                 is_exit = true;
             }
         },
-        Stmt::Assign(id, e) => {
+        Stmt::Assign(id, e, span) => {
+            let name = id.clone();
             let id = id_table.get(id);
-            if id.0.is_none() {
-                panic!("Unable to Find Variable");
-            }
-            match id.0.unwrap() {
-                IdElement::Var(_) => {}
-                _ => panic!("assign to non-variable"),
+            match id.0 {
+                Some(IdElement::Var(_)) => {}
+                Some(IdElement::Const(_)) => diag::emit(
+                    Diagnostic::error(
+                        format!("cannot assign to `{}` because it is a constant", name),
+                        Some(*span),
+                    )
+                    .with_code("E0002")
+                    .with_note("values declared with `const` cannot change after initialization"),
+                ),
+                Some(IdElement::Func(..)) => diag::emit(
+                    Diagnostic::error(format!("cannot assign to function `{}`", name), Some(*span))
+                        .with_code("E0002")
+                        .with_note("only variables can appear on the left of `=`"),
+                ),
+                None => undeclared(&name, *span, id_table),
             }
             let id = id.1;
             let tmp = expr2ir(e, id_table);
@@ -182,16 +748,16 @@ fn stmt2ir(stmt: &mut Stmt, id_table: &mut IdTable, cur_while_id: i32) -> (Strin
             out += &format!("store {}, @{}\n", pos, id);
         }
         Stmt::Expr(e) => match e {
-            Some(e) => {
-                out += &expr2ir(e, id_table).0;
-            }
+            // A call of its own may be of a `void` function.
+            Some(Expr::Func(ident, args, span)) => out += &call2ir(ident, args, *span, id_table).0,
+            Some(e) => out += &expr2ir(e, id_table).0,
             None => {}
         },
         Stmt::Block(b) => {
             let id = {
                 let mut counter_guard = BLOCK_COUNTER.lock().unwrap();
                 *counter_guard += 1;
-                counter_guard.clone()
+                *counter_guard
             };
             let mut table = IdTable::new(Some(id_table), id);
             let (st, is_exit_block) = &block2ir(b, &mut table, cur_while_id);
@@ -211,13 +777,13 @@ fn stmt2ir(stmt: &mut Stmt, id_table: &mut IdTable, cur_while_id: i32) -> (Strin
             let id = {
                 let mut counter_guard = IF_COUNTER.lock().unwrap();
                 *counter_guard += 1;
-                counter_guard.clone()
+                *counter_guard
             };
             match else_then {
                 None => {
                     out += &format!("br {}, %then_{}, %end_{}\n", cond, id, id);
                     out += &format!("%then_{}:\n", id);
-                    let (st, then_is_ret) = &stmt2ir(if_then, id_table, cur_while_id);
+                    let (st, then_is_ret) = &stmt2ir(&mut if_then.0, id_table, cur_while_id);
                     out += st;
                     if !then_is_ret {
                         out += &format!("jump %end_{}\n", id);
@@ -227,13 +793,13 @@ fn stmt2ir(stmt: &mut Stmt, id_table: &mut IdTable, cur_while_id: i32) -> (Strin
                 Some(else_then) => {
                     out += &format!("br {}, %then_{}, %else_{}\n", cond, id, id);
                     out += &format!("%then_{}:\n", id);
-                    let (st, then_is_ret) = &stmt2ir(if_then, id_table, cur_while_id);
+                    let (st, then_is_ret) = &stmt2ir(&mut if_then.0, id_table, cur_while_id);
                     out += st;
                     if !then_is_ret {
                         out += &format!("jump %end_{}\n", id);
                     }
                     out += &format!("%else_{}:\n", id);
-                    let (st, else_is_ret) = &stmt2ir(else_then.as_mut(), id_table, cur_while_id);
+                    let (st, else_is_ret) = &stmt2ir(&mut else_then.0, id_table, cur_while_id);
                     out += st;
                     if !else_is_ret {
                         out += &format!("jump %end_{}\n", id);
@@ -252,7 +818,7 @@ fn stmt2ir(stmt: &mut Stmt, id_table: &mut IdTable, cur_while_id: i32) -> (Strin
             out += &format!("%while_entry{}:\n", while_id);
             let tmp = expr2ir(cond, id_table);
             out += &tmp.0;
-            let pos = if tmp.0 == "" {
+            let pos = if tmp.0.is_empty() {
                 tmp.1.to_string()
             } else {
                 format!("%{}", tmp.1)
@@ -262,79 +828,146 @@ fn stmt2ir(stmt: &mut Stmt, id_table: &mut IdTable, cur_while_id: i32) -> (Strin
                 pos, while_id, while_id
             );
             out += &format!("%while_body{}:\n", while_id);
-            let (st, body_is_ret) = &stmt2ir(body, id_table, while_id);
+            let (st, body_is_ret) = &stmt2ir(&mut body.0, id_table, while_id);
             out += st;
             if !body_is_ret {
                 out += &format!("jump %while_entry{}\n", while_id);
             }
             out += &format!("%while_end{}:\n", while_id);
         }
-        Stmt::Break => {
+        Stmt::Break(span) => {
             if cur_while_id == -1 {
-                panic!("Break outside of while loop");
+                outside_loop("break", *span);
             }
             out += &format!("jump %while_end{}\n", cur_while_id);
             is_exit = true;
         }
-        Stmt::Continue => {
+        Stmt::Continue(span) => {
             if cur_while_id == -1 {
-                panic!("Continue outside of while loop");
+                outside_loop("continue", *span);
             }
             out += &format!("jump %while_entry{}\n", cur_while_id);
             is_exit = true;
         }
-        _ => todo!(),
     }
     (out, is_exit)
 }
 fn block2ir(block: &mut Block, id_table: &mut IdTable, cur_while_id: i32) -> (String, bool) {
     // println!("Block: {}\n", id_table.offset);
     let mut out = String::new();
+    // Statements after a `return`, `break` or `continue` are still checked
+    // for errors, but their code is dropped: the basic block has ended.
+    let mut exited = false;
+    let mut dropped = String::new();
     // let mut if_else_stack = Vec::new();
     for item in &mut block.items {
+        let out = if exited { &mut dropped } else { &mut out };
         // println!("{:#?}", item);
         match item {
             BlockItem::ConstDecl(clist) => {
                 for c in clist {
-                    let id = c.id.clone();
-                    let val = compute_expr(&c.value, id_table);
-                    id_table.insert(id, IdElement::Const(val));
+                    let val = const_init(c, id_table);
+                    id_table.define(c.id.clone(), IdElement::Const(val), c.span);
                 }
             }
             BlockItem::VarDecl(vlsit) => {
                 for v in vlsit {
                     let id = v.id.clone();
-                    out += &format!("@{}_{} = alloc {}\n", id, id_table.offset, "i32");
-                    if !v.value.is_none() {
-                        let tmp = expr2ir(&(v.value.as_ref()).unwrap(), id_table);
+                    // The initializer still sees an outer variable of the
+                    // same name.
+                    let init = v.value.as_ref().map(|value| expr2ir(value, id_table));
+                    if !id_table.define(id.clone(), IdElement::Var(String::from("i32")), v.span) {
+                        continue;
+                    }
+                    *out += &format!("@{}_{} = alloc {}\n", id, id_table.offset, "i32");
+                    if let Some(tmp) = init {
                         let pos = if tmp.0 == String::new() {
                             tmp.1.to_string()
                         } else {
                             format!("%{}", tmp.1)
                         };
-                        out += &tmp.0;
-                        out += &format!("store {}, @{}_{}\n", pos, id, id_table.offset);
+                        *out += &tmp.0;
+                        *out += &format!("store {}, @{}_{}\n", pos, id, id_table.offset);
                     }
-                    id_table.insert(id, IdElement::Var(String::from("i32")));
                 }
             }
-            BlockItem::Stmt(s) => {
+            BlockItem::Stmt(s, _) => {
                 let (st, is_exit_st) = &stmt2ir(s, id_table, cur_while_id);
-                out += st;
-                if *is_exit_st {
-                    return (out, true);
-                }
+                *out += st;
+                exited |= *is_exit_st;
             }
-            _ => unreachable!(),
         }
     }
-    (out, false)
+    (out, exited)
+}
+fn outside_loop(keyword: &str, span: Span) {
+    diag::emit(
+        Diagnostic::error(format!("`{}` outside of a loop", keyword), Some(span))
+            .with_code("E0004")
+            .with_note(format!(
+                "`{}` can only be used inside a `while` body",
+                keyword
+            )),
+    );
+}
+fn undeclared(name: &str, span: Span, id_table: &IdTable) {
+    let mut err = Diagnostic::error(
+        format!("use of undeclared identifier `{}`", name),
+        Some(span),
+    )
+    .with_code("E0001");
+    if let Some(similar) = suggest(name, id_table, false) {
+        err = err.with_note(format!("did you mean `{}`?", similar));
+    }
+    diag::emit(err);
+}
+/// The visible name closest to a misspelt `name`, looking at functions or at
+/// values only. Names further than a third of the length away are not
+/// offered, nor names as far away as `name` is long, such as `N` for `y`.
+fn suggest(name: &str, id_table: &IdTable, func: bool) -> Option<String> {
+    let mut names = id_table.names();
+    names.sort_by_key(|(n, _)| *n);
+    names
+        .into_iter()
+        .filter(|(_, element)| matches!(element, IdElement::Func(..)) == func)
+        .map(|(n, _)| (edit_distance(name, n), n))
+        .filter(|(d, _)| *d <= (name.len() / 3).max(1) && *d < name.len())
+        .min_by_key(|(d, _)| *d)
+        .map(|(_, n)| n.to_string())
 }
-fn compute_expr(expr: &Expr, id_table: &IdTable) -> i32 {
-    match expr {
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diag = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            // Case is ignored: a wrong case is the likeliest typo.
+            let cost = usize::from(!ca.eq_ignore_ascii_case(cb));
+            let next = (diag + cost).min(row[j] + 1).min(row[j + 1] + 1);
+            diag = row[j + 1];
+            row[j + 1] = next;
+        }
+    }
+    row[b.len()]
+}
+fn const_init(const_def: &ConstDef, id_table: &IdTable) -> i32 {
+    compute_expr(&const_def.value, id_table).unwrap_or_else(|err| {
+        diag::emit(err.with_note(format!(
+            "the initializer of `const` `{}` must be a constant expression",
+            const_def.id
+        )));
+        0
+    })
+}
+/// Evaluates a constant expression. Parts that cannot be known at compile
+/// time are returned as errors for the caller to explain; errors in an
+/// otherwise constant expression are reported here and evaluate to 0.
+pub(crate) fn compute_expr(expr: &Expr, id_table: &IdTable) -> Result<i32, Diagnostic> {
+    Ok(match expr {
         Expr::Number(n) => *n,
         Expr::UnaryExpr(op, expr) => {
-            let out = compute_expr(expr.as_ref(), id_table);
+            let out = compute_expr(expr.as_ref(), id_table)?;
             match op {
                 UnaryOp::Not => {
                     if out == 0 {
@@ -344,16 +977,54 @@ fn compute_expr(expr: &Expr, id_table: &IdTable) -> i32 {
                     }
                 }
                 UnaryOp::Plus => out,
-                UnaryOp::Minus => -out,
+                UnaryOp::Minus => out.wrapping_neg(),
             }
         }
-        Expr::BinaryExpr(lhs, op, rhs) => {
-            let lhs_val = compute_expr(lhs.as_ref(), id_table);
-            let rhs_val = compute_expr(rhs.as_ref(), id_table);
+        Expr::BinaryExpr(lhs, op, rhs, span) => {
+            let lhs_val = compute_expr(lhs.as_ref(), id_table)?;
+            // `&&` and `||` do not evaluate their right operand when the left
+            // one decides the result, so `0 && 1 / 0` is a valid constant.
+            match (op, lhs_val) {
+                (BinaryOp::And, 0) => return Ok(0),
+                (BinaryOp::Or, v) if v != 0 => return Ok(1),
+                _ => {}
+            }
+            let rhs_val = compute_expr(rhs.as_ref(), id_table)?;
+            // Arithmetic wraps around like the generated code does. The cases
+            // where the hardware result is not what C promises are errors.
             match op {
-                BinaryOp::Plus => lhs_val + rhs_val,
-                BinaryOp::Minus => lhs_val - rhs_val,
-                BinaryOp::Multiply => lhs_val * rhs_val,
+                BinaryOp::Plus => lhs_val.wrapping_add(rhs_val),
+                BinaryOp::Minus => lhs_val.wrapping_sub(rhs_val),
+                BinaryOp::Multiply => lhs_val.wrapping_mul(rhs_val),
+                BinaryOp::Divide | BinaryOp::Modulo if rhs_val == 0 => {
+                    let what = match op {
+                        BinaryOp::Divide => "division",
+                        _ => "remainder",
+                    };
+                    diag::emit(
+                        Diagnostic::error(
+                            format!("{} by zero in constant expression", what),
+                            Some(*span),
+                        )
+                        .with_code("E0009"),
+                    );
+                    0
+                }
+                BinaryOp::Divide | BinaryOp::Modulo if lhs_val == i32::MIN && rhs_val == -1 => {
+                    let what = match op {
+                        BinaryOp::Divide => "/",
+                        _ => "%",
+                    };
+                    diag::emit(
+                        Diagnostic::error(
+                            format!("`-2147483648 {} -1` overflows in constant expression", what),
+                            Some(*span),
+                        )
+                        .with_code("E0009")
+                        .with_note("the quotient 2147483648 is not representable as int"),
+                    );
+                    0
+                }
                 BinaryOp::Divide => lhs_val / rhs_val,
                 BinaryOp::Modulo => lhs_val % rhs_val,
                 BinaryOp::Less => {
@@ -414,17 +1085,124 @@ fn compute_expr(expr: &Expr, id_table: &IdTable) -> i32 {
                 }
             }
         }
-        Expr::LVal(lval) => {
-            let out = id_table.get(lval);
-            if out.0.is_none() {
-                panic!("Unable to Find Value")
+        Expr::LVal(lval, span) => match id_table.get(lval).0 {
+            Some(IdElement::Const(c)) => *c,
+            Some(IdElement::Var(_)) => {
+                return Err(Diagnostic::error(
+                    format!("`{}` is a variable, not a constant", lval),
+                    Some(*span),
+                )
+                .with_code("E0003"))
+            }
+            Some(IdElement::Func(..)) => {
+                return Err(Diagnostic::error(
+                    format!("`{}` is a function, not a value", lval),
+                    Some(*span),
+                )
+                .with_code("E0008"))
             }
-            match out.0.unwrap() {
-                IdElement::Const(c) => *c,
-                _ => panic!("Unable to calculate"),
+            None => {
+                undeclared(lval, *span, id_table);
+                0
             }
+        },
+        Expr::Func(ident, _, span) => {
+            return Err(Diagnostic::error(
+                format!("call to `{}` in a constant expression", ident),
+                Some(*span),
+            )
+            .with_code("E0003")
+            .with_note("function calls are evaluated at run time"))
+        }
+    })
+}
+/// Lowers a call, whose result, if it has one, is left in the returned
+/// register.
+fn call2ir(ident: &String, args: &[Expr], span: Span, id_table: &IdTable) -> (String, i32) {
+    match id_table.get(ident).0 {
+        Some(IdElement::Func(kind, params)) => {
+            if args.len() != *params {
+                diag::emit(
+                    Diagnostic::error(
+                        format!(
+                            "`{}` takes {} argument{} but {} {} given",
+                            ident,
+                            params,
+                            if *params == 1 { "" } else { "s" },
+                            args.len(),
+                            if args.len() == 1 { "was" } else { "were" }
+                        ),
+                        Some(span),
+                    )
+                    .with_code("E0016"),
+                );
+            }
+            let mut out = String::new();
+            let mut out_reg_id = -1;
+            if kind == "void" {
+                let mut tmp = format!("call @{}(", ident);
+                for i in 0..args.len() {
+                    let arg = &args[i];
+                    let (st, pos) = expr2ir(arg, id_table);
+                    out += &st;
+                    if st == String::new() {
+                        tmp += &format!("{}", pos);
+                    } else {
+                        tmp += &format!("%{}", pos);
+                    }
+                    if i < args.len() - 1 {
+                        tmp += ", ";
+                    }
+                }
+                tmp += ")\n";
+                out += &tmp;
+            } else {
+                out_reg_id = {
+                    let mut counter = COUNTER.lock().unwrap();
+                    *counter += 1;
+                    *counter
+                };
+                let mut tmp = format!("%{} = call @{}(", out_reg_id, ident);
+                for i in 0..args.len() {
+                    let arg = &args[i];
+                    let (st, pos) = expr2ir(arg, id_table);
+                    out += &st;
+                    if st == String::new() {
+                        tmp += &format!("{}", pos);
+                    } else {
+                        tmp += &format!("%{}", pos);
+                    }
+                    if i < args.len() - 1 {
+                        tmp += ", ";
+                    }
+                }
+                tmp += ")\n";
+                out += &tmp;
+            }
+            (out, out_reg_id)
+        }
+        Some(_) => {
+            diag::emit(
+                Diagnostic::error(
+                    format!("called object `{}` is not a function", ident),
+                    Some(span),
+                )
+                .with_code("E0008"),
+            );
+            (String::new(), 0)
+        }
+        None => {
+            let mut err = Diagnostic::error(
+                format!("call to undeclared function `{}`", ident),
+                Some(span),
+            )
+            .with_code("E0001");
+            if let Some(similar) = suggest(ident, id_table, true) {
+                err = err.with_note(format!("did you mean `{}`?", similar));
+            }
+            diag::emit(err);
+            (String::new(), 0)
         }
-        _ => unreachable!(),
     }
 }
 fn expr2ir(exp: &Expr, id_table: &IdTable) -> (String, i32) {
@@ -435,8 +1213,8 @@ fn expr2ir(exp: &Expr, id_table: &IdTable) -> (String, i32) {
             let out = expr2ir(expr, id_table);
             let mut counter = COUNTER.lock().unwrap();
             *counter += 1;
-            let counter = counter.clone();
-            let pos = if out.0 == "".to_string() {
+            let counter = *counter;
+            let pos = if out.0.is_empty() {
                 out.1.to_string()
             } else {
                 format!("%{}", out.1)
@@ -447,7 +1225,7 @@ fn expr2ir(exp: &Expr, id_table: &IdTable) -> (String, i32) {
                 UnaryOp::Plus => (out.0, out.1),
             }
         }
-        Expr::BinaryExpr(lhs, op, rhs) => {
+        Expr::BinaryExpr(lhs, op, rhs, _) => {
             let lout = expr2ir(lhs, id_table);
             let lpos = if lout.0 == String::new() {
                 lout.1.to_string()
@@ -588,70 +1366,250 @@ fn expr2ir(exp: &Expr, id_table: &IdTable) -> (String, i32) {
                 } // _ => unreachable!(),
             }
         }
-        Expr::LVal(lval) => {
+        Expr::LVal(lval, span) => {
             let element = id_table.get(lval);
-            if element.0.is_none() {
-                panic!("Unable to Find Value")
-            }
-            match element.0.unwrap() {
-                IdElement::Const(val) => (String::new(), *val),
-                IdElement::Var(_) => {
+            match element.0 {
+                Some(IdElement::Const(val)) => (String::new(), *val),
+                Some(IdElement::Var(_)) => {
                     let mut counter = COUNTER.lock().unwrap();
                     *counter += 1;
                     let out = format!("%{} = load @{}\n", *counter, element.1);
                     (out, *counter)
                 }
-                _ => todo!(),
+                Some(IdElement::Func(..)) => {
+                    diag::emit(
+                        Diagnostic::error(
+                            format!("`{}` is a function, not a value", lval),
+                            Some(*span),
+                        )
+                        .with_code("E0008")
+                        .with_note(format!("to call it, write `{}(...)`", lval)),
+                    );
+                    (String::new(), 0)
+                }
+                None => {
+                    undeclared(lval, *span, id_table);
+                    (String::new(), 0)
+                }
             }
         }
-        Expr::Func(ident, args) => match id_table.get(ident).0.unwrap() {
-            IdElement::Func(kind) => {
-                let mut out = String::new();
-                let mut out_reg_id = -1;
+        Expr::Func(ident, args, span) => {
+            if let (Some(IdElement::Func(kind, _)), _) = id_table.get(ident) {
                 if kind == "void" {
-                    let mut tmp = format!("call @{}(", ident);
-                    for i in 0..args.len() {
-                        let arg = &args[i];
-                        let (st, pos) = expr2ir(arg, id_table);
-                        out += &st;
-                        if st == String::new() {
-                            tmp += &format!("{}", pos);
-                        } else {
-                            tmp += &format!("%{}", pos);
-                        }
-                        if i < args.len() - 1 {
-                            tmp += ", ";
-                        }
-                    }
-                    tmp += ")\n";
-                    out += &tmp;
-                } else {
-                    out_reg_id = {
-                        let mut counter = COUNTER.lock().unwrap();
-                        *counter += 1;
-                        *counter
-                    };
-                    let mut tmp = format!("%{} = call @{}(", out_reg_id, ident);
-                    for i in 0..args.len() {
-                        let arg = &args[i];
-                        let (st, pos) = expr2ir(arg, id_table);
-                        out += &st;
-                        if st == String::new() {
-                            tmp += &format!("{}", pos);
-                        } else {
-                            tmp += &format!("%{}", pos);
-                        }
-                        if i < args.len() - 1 {
-                            tmp += ", ";
-                        }
-                    }
-                    tmp += ")\n";
-                    out += &tmp;
+                    diag::emit(
+                        Diagnostic::error(
+                            format!("`{}` does not return a value", ident),
+                            Some(*span),
+                        )
+                        .with_code("E0017")
+                        .with_note(format!(
+                            "`{}` is declared `void`, so it can only be called as a statement",
+                            ident
+                        )),
+                    );
                 }
-                (out, out_reg_id)
             }
-            _ => panic!("Not a Function"),
-        },
-        _ => todo!(),
+            call2ir(ident, args, *span, id_table)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+
+    /// Folds `expr` as the initializer of a constant, with the messages of
+    /// the errors it causes.
+    fn fold(expr: &str) -> (i32, Vec<String>) {
+        let text = format!("const int c = {};", expr);
+        let unit = crate::sysy::CompUnitParser::new()
+            .parse(Lexer::new(&text, 0))
+            .unwrap();
+        let CompItem::ConstDecl(defs) = &unit.list[0] else {
+            unreachable!()
+        };
+        let value = compute_expr(&defs[0].value, &IdTable::new(None, 0)).unwrap_or_else(|err| {
+            diag::emit(err);
+            0
+        });
+        (value, diag::take().into_iter().map(|d| d.message).collect())
+    }
+
+    /// Checks a whole program, with the errors it causes.
+    fn check(src: &str) -> Vec<Diagnostic> {
+        let mut source_map = SourceMap::default();
+        let base = source_map.add("t.c", src);
+        let unit = crate::sysy::CompUnitParser::new()
+            .parse(Lexer::new(src, base))
+            .unwrap();
+        ast2ir_part(&mut [unit], &Runtime::default(), &source_map);
+        diag::take()
+    }
+
+    /// The modules of a whole program, with the functions each defines.
+    fn modules(src: &str) -> (Vec<Module>, Vec<Vec<String>>) {
+        let mut source_map = SourceMap::default();
+        let base = source_map.add("t.c", src);
+        let unit = crate::sysy::CompUnitParser::new()
+            .parse(Lexer::new(src, base))
+            .unwrap();
+        let modules = ast2ir(&mut [unit], &Runtime::default(), &source_map);
+        assert!(diag::take().is_empty());
+        let funcs = modules
+            .iter()
+            .map(|module| {
+                module
+                    .koopa
+                    .lines()
+                    .filter_map(|line| line.strip_prefix("fun @"))
+                    .map(|rest| rest[..rest.find('(').unwrap()].to_string())
+                    .collect()
+            })
+            .collect();
+        (modules, funcs)
+    }
+
+    #[test]
+    fn calls_to_later_functions_stay_in_one_module() {
+        let (modules, funcs) = modules(
+            "int f(); int g(); int main() { return f() + g(); } int f() { return g(); } int g() { return 1; }",
+        );
+        assert_eq!(funcs, [["g", "f", "main"]]);
+        assert!(!modules[0].koopa.contains("decl @f("));
+        assert!(!modules[0].koopa.contains("decl @g("));
+    }
+
+    #[test]
+    fn only_mutual_recursion_splits_modules() {
+        let (modules, funcs) = modules(
+            "int odd(int n); int even(int n) { if (n) return odd(n - 1); return 1; }
+int odd(int n) { if (n) return even(n - 1); return 0; }
+int main() { return even(4); }",
+        );
+        assert_eq!(funcs, [vec!["odd"], vec!["even", "main"]]);
+        assert!(modules[0].koopa.contains("decl @even("));
+    }
+
+    #[test]
+    fn globals_keep_their_names_as_symbols() {
+        let (modules, _) = modules("int g = 1; extern int h; int h; int main() { return g + h; }");
+        let mut symbols: Vec<&str> = modules[0].symbols.values().map(String::as_str).collect();
+        symbols.sort();
+        assert_eq!(symbols, ["g", "h"]);
+        for (koopa, symbol) in modules[0].symbols.iter() {
+            assert!(modules[0].koopa.contains(&format!("global @{} ", koopa)));
+            assert_ne!(koopa, symbol);
+        }
+    }
+
+    #[test]
+    fn arithmetic_wraps_around() {
+        assert_eq!(fold("2147483647 + 1"), (i32::MIN, vec![]));
+        assert_eq!(fold("-2147483648 - 1"), (i32::MAX, vec![]));
+        assert_eq!(fold("65536 * 65536"), (0, vec![]));
+        assert_eq!(fold("-7 / 2 + -7 % 2"), (-4, vec![]));
+    }
+
+    #[test]
+    fn division_by_zero_is_an_error() {
+        assert_eq!(
+            fold("1 / 0"),
+            (
+                0,
+                vec!["division by zero in constant expression".to_string()]
+            )
+        );
+        assert_eq!(
+            fold("1 % (2 - 2)"),
+            (
+                0,
+                vec!["remainder by zero in constant expression".to_string()]
+            )
+        );
+    }
+
+    #[test]
+    fn int_min_divided_by_minus_one_is_an_error() {
+        assert_eq!(
+            fold("-2147483648 / -1"),
+            (
+                0,
+                vec!["`-2147483648 / -1` overflows in constant expression".to_string()]
+            )
+        );
+        assert_eq!(
+            fold("-2147483648 % -1"),
+            (
+                0,
+                vec!["`-2147483648 % -1` overflows in constant expression".to_string()]
+            )
+        );
+    }
+
+    #[test]
+    fn short_circuit_skips_the_right_operand() {
+        assert_eq!(fold("0 && 1 / 0"), (0, vec![]));
+        assert_eq!(fold("2 || 1 / 0"), (1, vec![]));
+    }
+
+    #[test]
+    fn redefinitions_point_at_the_first_definition() {
+        let src = "int f(int p) { int a = 1; int a = 2; const int c = 1; int c; int p; return a; }";
+        let errors = check(src);
+        let found: Vec<(&str, &str, &str)> = errors
+            .iter()
+            .map(|d| {
+                let span = d.span.unwrap();
+                let (first, _) = d.related[0];
+                (
+                    d.message.as_str(),
+                    &src[span.lo..span.hi],
+                    &src[first.lo..first.hi],
+                )
+            })
+            .collect();
+        assert_eq!(
+            found,
+            [
+                ("redefinition of `a`", "a", "a"),
+                ("redefinition of `c`", "c", "c"),
+                ("redefinition of `p`", "p", "p"),
+            ]
+        );
+        assert!(errors.iter().all(|d| d.code == Some("E0005")));
+        assert_eq!(errors[0].related[0].0.lo, src.find("a = 1").unwrap());
+    }
+
+    #[test]
+    fn shadowing_in_an_inner_scope_is_not_a_redefinition() {
+        assert!(check("int a; int f(int a) { { int a = a; } return a; }").is_empty());
+        assert_eq!(
+            check("const int a = 1; int a;")
+                .into_iter()
+                .map(|d| d.message)
+                .collect::<Vec<_>>(),
+            ["redefinition of `a`"]
+        );
+    }
+
+    #[test]
+    fn void_calls_have_no_value() {
+        let src = "void g() {} int main() { g(); putint(1); int x = g(); return putch(x) + 1; }";
+        let errors = check(src);
+        let found: Vec<(&str, &str, Option<&str>)> = errors
+            .iter()
+            .map(|d| {
+                let span = d.span.unwrap();
+                (d.message.as_str(), &src[span.lo..span.hi], d.code)
+            })
+            .collect();
+        assert_eq!(
+            found,
+            [
+                ("`g` does not return a value", "g", Some("E0017")),
+                ("`putch` does not return a value", "putch", Some("E0017")),
+            ]
+        );
     }
 }
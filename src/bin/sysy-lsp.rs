@@ -0,0 +1,431 @@
+//! A language server for SysY, speaking LSP over stdin and stdout. It runs
+//! the compiler's front end on every change and answers from the result:
+//! diagnostics, go to definition, references, hover and document symbols.
+
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::process::exit;
+use sysy::diag::{Diagnostic, Severity, SourceMap, Span};
+use sysy::symbols::{Index, SymbolKind};
+use sysy::{ast2ir, diag, lexer, lint, preprocess, runtime, symbols, CompUnitParser};
+
+/// The result of checking one open document.
+struct Analysis {
+    path: String,
+    /// The lines of the document as the editor has it, for positions.
+    lines: Vec<String>,
+    source_map: SourceMap,
+    diagnostics: Vec<Diagnostic>,
+    /// Missing when the document does not parse.
+    index: Option<Index>,
+}
+
+fn analyze(path: &str, text: &str) -> Analysis {
+    let mut analysis = Analysis {
+        path: path.to_string(),
+        lines: text.lines().map(str::to_string).collect(),
+        source_map: SourceMap::default(),
+        diagnostics: Vec::new(),
+        index: None,
+    };
+    let runtime = runtime::Runtime::default();
+    let source = match preprocess::preprocess_source(path, text, &mut analysis.source_map) {
+        Ok(source) => source,
+        Err(err) => {
+            analysis.diagnostics.push(err);
+            return analysis;
+        }
+    };
+    let base = analysis.source_map.add(path, &source);
+    let mut unit = match CompUnitParser::new().parse(lexer::Lexer::new(&source, base)) {
+        Ok(unit) => unit,
+        Err(err) => {
+            analysis.diagnostics.push(err.into());
+            return analysis;
+        }
+    };
+    // Indexed first: `ast2ir` rewrites calls such as `starttime()`.
+    let index = symbols::index(&unit, &runtime);
+    diag::take();
+    let source_map = &analysis.source_map;
+    // Code generation is not finished for every construct; a panic there
+    // must not take the server down.
+    let checked = panic::catch_unwind(AssertUnwindSafe(|| {
+        // The other files of the program are unknown.
+        ast2ir::ast2ir_part(std::slice::from_mut(&mut unit), &runtime, source_map);
+        lint::check(
+            std::slice::from_ref(&unit),
+            &runtime,
+            &lint::Config::default(),
+        );
+    }));
+    analysis.diagnostics = diag::take();
+    if checked.is_ok() {
+        analysis.index = Some(index);
+    }
+    analysis
+}
+
+fn uri_to_path(uri: &str) -> String {
+    let path = uri.strip_prefix("file://").unwrap_or(uri);
+    let bytes = path.as_bytes();
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = path
+            .get(i + 1..i + 3)
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                out.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                out.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+impl Analysis {
+    /// The LSP range of a span, if it lies in the document itself rather
+    /// than in an included file.
+    fn range(&self, span: Span) -> Option<Value> {
+        if self.source_map.lookup(span.lo).file != self.path {
+            return None;
+        }
+        let (start_line, start_character) = self.position(span.lo);
+        let (end_line, end_character) = self.position(span.hi.max(span.lo));
+        Some(json!({
+            "start": { "line": start_line, "character": start_character },
+            "end": { "line": end_line, "character": end_character },
+        }))
+    }
+
+    /// The 0-based line and character of a position in the document.
+    /// Characters are counted in UTF-16 code units, the encoding LSP
+    /// positions use by default, on the document's own text: the
+    /// preprocessor blanks out each byte of a comment.
+    fn position(&self, pos: usize) -> (u64, u64) {
+        let loc = self.source_map.lookup(pos);
+        let text = self.lines.get(loc.line - 1).map_or("", String::as_str);
+        let before = text.get(..loc.col - 1).unwrap_or(text);
+        (loc.line as u64 - 1, before.encode_utf16().count() as u64)
+    }
+
+    fn contains(&self, span: Span, line: u64, character: u64) -> bool {
+        let (start_line, start) = self.position(span.lo);
+        let (_, end) = self.position(span.hi);
+        self.source_map.lookup(span.lo).file == self.path
+            && start_line == line
+            && (start..=end).contains(&character)
+    }
+
+    fn diagnostics(&self, uri: &str) -> Vec<Value> {
+        self.diagnostics
+            .iter()
+            .map(|d| {
+                let mut message = d.message.clone();
+                for note in d.notes.iter() {
+                    message += &format!("\nnote: {}", note);
+                }
+                let start = json!({ "line": 0, "character": 0 });
+                let mut range = json!({ "start": start, "end": start });
+                if let Some(span) = d.span {
+                    match self.range(span) {
+                        Some(r) => range = r,
+                        None => {
+                            // Errors in included files are shown at the top.
+                            let loc = self.source_map.lookup(span.lo);
+                            message = format!("{}:{}: {}", loc.file, loc.line, message);
+                        }
+                    }
+                }
+                let related: Vec<Value> = d
+                    .related
+                    .iter()
+                    .filter_map(|(span, message)| {
+                        Some(json!({
+                            "location": { "uri": uri, "range": self.range(*span)? },
+                            "message": message,
+                        }))
+                    })
+                    .collect();
+                json!({
+                    "range": range,
+                    "severity": match d.severity {
+                        Severity::Error => 1,
+                        Severity::Warning => 2,
+                    },
+                    "code": d.rule(),
+                    "source": "sysy",
+                    "message": message,
+                    "relatedInformation": related,
+                })
+            })
+            .collect()
+    }
+
+    fn symbol_at(&self, params: &Value) -> Option<(&Index, usize)> {
+        let index = self.index.as_ref()?;
+        let line = params["position"]["line"].as_u64()?;
+        let character = params["position"]["character"].as_u64()?;
+        let symbol = index.find(|span| self.contains(span, line, character))?;
+        Some((index, symbol))
+    }
+
+    fn definition(&self, uri: &str, params: &Value) -> Value {
+        self.symbol_at(params)
+            .and_then(|(index, symbol)| self.range(index.symbols[symbol].span?))
+            .map_or(Value::Null, |range| json!({ "uri": uri, "range": range }))
+    }
+
+    fn references(&self, uri: &str, params: &Value) -> Value {
+        let Some((index, symbol)) = self.symbol_at(params) else {
+            return json!([]);
+        };
+        let mut spans: Vec<Span> = index.references_to(symbol).collect();
+        if params["context"]["includeDeclaration"]
+            .as_bool()
+            .unwrap_or(true)
+        {
+            spans.extend(index.symbols[symbol].span);
+        }
+        spans.sort_by_key(|span| span.lo);
+        let locations: Vec<Value> = spans
+            .into_iter()
+            .filter_map(|span| self.range(span))
+            .map(|range| json!({ "uri": uri, "range": range }))
+            .collect();
+        json!(locations)
+    }
+
+    fn hover(&self, params: &Value) -> Value {
+        let Some((index, symbol)) = self.symbol_at(params) else {
+            return Value::Null;
+        };
+        let symbol = &index.symbols[symbol];
+        let mut value = format!("```c\n{}\n```", symbol.detail);
+        match (symbol.kind, symbol.span) {
+            (SymbolKind::Function, None) => value += "\n\nSysY runtime library",
+            (SymbolKind::Parameter, _) => value += "\n\nparameter",
+            _ => {}
+        }
+        json!({ "contents": { "kind": "markdown", "value": value } })
+    }
+
+    fn document_symbols(&self) -> Value {
+        let Some(index) = &self.index else {
+            return json!([]);
+        };
+        let symbol = |i: usize| {
+            let s = &index.symbols[i];
+            let range = self.range(s.span?)?;
+            let kind = match s.kind {
+                SymbolKind::Function => 12,
+                SymbolKind::Variable | SymbolKind::Parameter => 13,
+                SymbolKind::Constant => 14,
+            };
+            Some(json!({
+                "name": s.name,
+                "detail": s.detail,
+                "kind": kind,
+                "range": range,
+                "selectionRange": range,
+            }))
+        };
+        // A function's symbol moves to its definition, which can come after
+        // other declarations; list them in source order.
+        let mut top_level: Vec<usize> = (0..index.symbols.len())
+            .filter(|i| index.symbols[*i].parent.is_none())
+            .collect();
+        top_level.sort_by_key(|i| index.symbols[*i].span.map(|s| s.lo));
+        let symbols: Vec<Value> = top_level
+            .into_iter()
+            .filter_map(|i| {
+                let mut top = symbol(i)?;
+                let children: Vec<Value> = (0..index.symbols.len())
+                    .filter(|c| index.symbols[*c].parent == Some(i))
+                    .filter_map(symbol)
+                    .collect();
+                top["children"] = json!(children);
+                Some(top)
+            })
+            .collect();
+        json!(symbols)
+    }
+}
+
+fn read_message(input: &mut impl BufRead) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(value) = line.strip_prefix("Content-Length:") {
+            length = value.trim().parse().ok();
+        }
+    }
+    let mut body = vec![0; length.unwrap_or(0)];
+    input.read_exact(&mut body)?;
+    Ok(Some(serde_json::from_slice(&body).unwrap_or(Value::Null)))
+}
+
+fn send(output: &mut impl Write, message: Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+struct Server {
+    documents: HashMap<String, Analysis>,
+    shutdown: bool,
+}
+
+impl Server {
+    fn update(&mut self, uri: &str, text: &str) -> Value {
+        let analysis = analyze(&uri_to_path(uri), text);
+        let notification = json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/publishDiagnostics",
+            "params": { "uri": uri, "diagnostics": analysis.diagnostics(uri) },
+        });
+        self.documents.insert(uri.to_string(), analysis);
+        notification
+    }
+
+    /// Handles a notification, returning the notifications to send back.
+    fn notify(&mut self, method: &str, params: &Value) -> Vec<Value> {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+        match method {
+            "textDocument/didOpen" => {
+                let text = params["textDocument"]["text"].as_str().unwrap_or_default();
+                vec![self.update(uri, text)]
+            }
+            "textDocument/didChange" => {
+                // Full synchronization: the last change is the whole text.
+                match params["contentChanges"].as_array().and_then(|c| c.last()) {
+                    Some(change) => {
+                        vec![self.update(uri, change["text"].as_str().unwrap_or_default())]
+                    }
+                    None => Vec::new(),
+                }
+            }
+            "textDocument/didClose" => {
+                self.documents.remove(uri);
+                vec![json!({
+                    "jsonrpc": "2.0",
+                    "method": "textDocument/publishDiagnostics",
+                    "params": { "uri": uri, "diagnostics": [] },
+                })]
+            }
+            "exit" => exit(if self.shutdown { 0 } else { 1 }),
+            _ => Vec::new(),
+        }
+    }
+
+    fn request(&mut self, method: &str, params: &Value) -> Result<Value, (i64, String)> {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+        let document = self.documents.get(uri);
+        match (method, document) {
+            ("initialize", _) => Ok(json!({
+                "capabilities": {
+                    "textDocumentSync": 1,
+                    "definitionProvider": true,
+                    "referencesProvider": true,
+                    "hoverProvider": true,
+                    "documentSymbolProvider": true,
+                },
+                "serverInfo": { "name": "sysy-lsp", "version": env!("CARGO_PKG_VERSION") },
+            })),
+            ("shutdown", _) => {
+                self.shutdown = true;
+                Ok(Value::Null)
+            }
+            ("textDocument/definition", Some(doc)) => Ok(doc.definition(uri, params)),
+            ("textDocument/references", Some(doc)) => Ok(doc.references(uri, params)),
+            ("textDocument/hover", Some(doc)) => Ok(doc.hover(params)),
+            ("textDocument/documentSymbol", Some(doc)) => Ok(doc.document_symbols()),
+            (
+                "textDocument/definition"
+                | "textDocument/references"
+                | "textDocument/hover"
+                | "textDocument/documentSymbol",
+                None,
+            ) => Err((-32602, format!("`{}` is not open", uri))),
+            _ => Err((-32601, format!("unsupported method `{}`", method))),
+        }
+    }
+}
+
+fn main() -> io::Result<()> {
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    let mut output = io::stdout();
+    let mut server = Server {
+        documents: HashMap::new(),
+        shutdown: false,
+    };
+    while let Some(message) = read_message(&mut input)? {
+        let method = message["method"].as_str().unwrap_or_default();
+        let params = &message["params"];
+        match message.get("id") {
+            Some(id) => {
+                let response = match server.request(method, params) {
+                    Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+                    Err((code, error)) => json!({
+                        "jsonrpc": "2.0",
+                        "id": id,
+                        "error": { "code": code, "message": error },
+                    }),
+                };
+                send(&mut output, response)?;
+            }
+            None => {
+                for notification in server.notify(method, params) {
+                    send(&mut output, notification)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn characters_are_utf16_code_units() {
+        let doc = analyze("t.c", "/* é𝄞 */ int x;\nint main() { return x; }\n");
+        let index = doc.index.as_ref().unwrap();
+        let x = index.symbols.iter().find(|s| s.name == "x").unwrap();
+        // `é` is one code unit and `𝄞` two, though they take five bytes.
+        assert_eq!(
+            doc.range(x.span.unwrap()),
+            Some(json!({
+                "start": { "line": 0, "character": 14 },
+                "end": { "line": 0, "character": 15 },
+            }))
+        );
+        assert!(doc.contains(x.span.unwrap(), 0, 14));
+        assert!(!doc.contains(x.span.unwrap(), 0, 18));
+    }
+
+    #[test]
+    fn timing_calls_are_indexed_as_written() {
+        let doc = analyze("t.c", "int main() { starttime(); return 0; }\n");
+        let index = doc.index.as_ref().unwrap();
+        let symbol = index.find(|span| doc.contains(span, 0, 14)).unwrap();
+        assert_eq!(index.symbols[symbol].detail, "void starttime()");
+    }
+}
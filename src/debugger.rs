@@ -0,0 +1,393 @@
+//! A source-level debugger on top of the interpreter: breakpoints by line,
+//! stepping by statement, variables by their SysY names and a backtrace.
+//! Commands are read from the program's own input, like its `getint`s.
+
+use crate::diag::{Diagnostic, SourceMap, Span};
+use crate::interp::{Hook, Interpreter};
+use std::io::{self, IsTerminal};
+
+const HELP: &str = "\
+  break|b [FILE:]LINE   stop whenever LINE is reached
+  delete|d [N]          remove breakpoint N, or all of them
+  info breakpoints      list the breakpoints
+  step|s                run to the next statement, into calls
+  next|n                run to the next statement, over calls
+  finish                run until the current function returns
+  continue|c            run to the next breakpoint
+  print|p NAME          show a variable or constant
+  locals                show the locals in scope
+  globals               show the globals
+  backtrace|bt          show the running calls
+  quit|q                stop the program
+An empty line repeats the last command.
+";
+
+enum Mode {
+    Step,
+    /// Stop in a call at most this deep.
+    Next(usize),
+    /// Stop in a call less deep than this.
+    Finish(usize),
+    Continue,
+}
+
+pub struct Debugger<'a> {
+    source_map: &'a SourceMap,
+    /// The file a breakpoint given by its line alone is in.
+    file: String,
+    /// Numbered from 1; deleted breakpoints keep their number.
+    breakpoints: Vec<Option<(String, usize)>>,
+    mode: Mode,
+    /// The statement reached last and the depth of its call.
+    prev: Option<(Span, usize)>,
+    last_command: String,
+    interactive: bool,
+}
+
+impl<'a> Debugger<'a> {
+    /// A debugger that stops at the first statement of `main`. Breakpoints
+    /// given as a bare line number refer to `file`.
+    pub fn new(source_map: &'a SourceMap, file: &str) -> Self {
+        Debugger {
+            source_map,
+            file: file.to_string(),
+            breakpoints: Vec::new(),
+            mode: Mode::Step,
+            prev: None,
+            last_command: String::new(),
+            interactive: io::stdin().is_terminal(),
+        }
+    }
+
+    fn place(&self, span: Span) -> String {
+        let loc = self.source_map.lookup(span.lo);
+        format!("{}:{}", loc.file, loc.line)
+    }
+
+    /// Whether `at` is where the program first reaches a new line. Later
+    /// statements on the same line do not count, but coming back to the
+    /// line, as a loop does, does.
+    fn enters_line(&self, at: Span, depth: usize) -> bool {
+        match self.prev {
+            Some((prev, prev_depth)) => {
+                let (a, b) = (
+                    self.source_map.lookup(prev.lo),
+                    self.source_map.lookup(at.lo),
+                );
+                prev_depth != depth || prev.lo >= at.lo || a.file != b.file || a.line != b.line
+            }
+            None => true,
+        }
+    }
+
+    fn breakpoint_at(&self, at: Span) -> Option<usize> {
+        let loc = self.source_map.lookup(at.lo);
+        self.breakpoints.iter().position(|bp| {
+            bp.as_ref()
+                .is_some_and(|(file, line)| *file == loc.file && *line == loc.line)
+        })
+    }
+
+    fn add_breakpoint(&mut self, arg: &str) -> String {
+        let (file, line) = match arg.rsplit_once(':') {
+            Some((file, line)) => (file.to_string(), line),
+            None => (self.file.clone(), arg),
+        };
+        match line.parse::<usize>() {
+            Ok(line) if line > 0 => {
+                self.breakpoints.push(Some((file.clone(), line)));
+                format!(
+                    "Breakpoint {} at {}:{}\n",
+                    self.breakpoints.len(),
+                    file,
+                    line
+                )
+            }
+            _ => format!(
+                "`{}` is not a line, try `break 12` or `break a.c:12`\n",
+                arg
+            ),
+        }
+    }
+
+    fn delete(&mut self, arg: &str) -> String {
+        if arg.is_empty() {
+            self.breakpoints.iter_mut().for_each(|bp| *bp = None);
+            return "Deleted all breakpoints\n".to_string();
+        }
+        let slot = arg
+            .parse::<usize>()
+            .ok()
+            .and_then(|n| self.breakpoints.get_mut(n.wrapping_sub(1)))
+            .filter(|bp| bp.is_some());
+        match slot {
+            Some(bp) => {
+                *bp = None;
+                format!("Deleted breakpoint {}\n", arg)
+            }
+            None => format!("No breakpoint {}\n", arg),
+        }
+    }
+
+    fn breakpoints(&self) -> String {
+        let mut out = String::new();
+        for (i, bp) in self.breakpoints.iter().enumerate() {
+            if let Some((file, line)) = bp {
+                out += &format!("{}\t{}:{}\n", i + 1, file, line);
+            }
+        }
+        if out.is_empty() {
+            out += "No breakpoints\n";
+        }
+        out
+    }
+
+    fn backtrace(&self, interp: &Interpreter<'_>) -> String {
+        let mut out = String::new();
+        for (i, (func, at)) in interp.backtrace().into_iter().enumerate() {
+            out += &format!("#{:<3}{}", i, func);
+            if let Some(at) = at {
+                out += &format!(" at {}", self.place(at));
+            }
+            out += "\n";
+        }
+        out
+    }
+
+    /// Runs one command. Returns the mode to go on in for commands that
+    /// resume the program.
+    fn command(&mut self, interp: &Interpreter<'_>, line: &str, out: &mut String) -> Option<Mode> {
+        let (name, arg) = line.split_once(' ').unwrap_or((line, ""));
+        let arg = arg.trim();
+        let depth = interp.depth();
+        match name {
+            "step" | "s" => return Some(Mode::Step),
+            "next" | "n" => return Some(Mode::Next(depth)),
+            "finish" => return Some(Mode::Finish(depth)),
+            "continue" | "c" => return Some(Mode::Continue),
+            "break" | "b" => *out += &self.add_breakpoint(arg),
+            "delete" | "d" => *out += &self.delete(arg),
+            "info" if arg == "breakpoints" => *out += &self.breakpoints(),
+            "print" | "p" => match interp.value(arg) {
+                Some(value) => *out += &format!("{} = {}\n", arg, value),
+                None => *out += &format!("No variable `{}` in scope\n", arg),
+            },
+            "locals" | "globals" => {
+                let vars = match name {
+                    "locals" => interp.locals(),
+                    _ => interp.globals(),
+                };
+                for (name, value) in vars.iter() {
+                    *out += &format!("{} = {}\n", name, value);
+                }
+                if vars.is_empty() {
+                    *out += &format!("No {}\n", name);
+                }
+            }
+            "backtrace" | "bt" => *out += &self.backtrace(interp),
+            "help" | "h" => *out += HELP,
+            _ => *out += &format!("Unknown command `{}`, try `help`\n", line),
+        }
+        None
+    }
+}
+
+impl Hook for Debugger<'_> {
+    fn before(&mut self, interp: &mut Interpreter<'_>, at: Span) -> Result<(), Diagnostic> {
+        let depth = interp.depth();
+        let breakpoint = match self.enters_line(at, depth) {
+            true => self.breakpoint_at(at),
+            false => None,
+        };
+        self.prev = Some((at, depth));
+        let stop = match self.mode {
+            Mode::Step => true,
+            Mode::Next(d) => depth <= d,
+            Mode::Finish(d) => depth < d,
+            Mode::Continue => false,
+        };
+        if !stop && breakpoint.is_none() {
+            return Ok(());
+        }
+        let io_error = |e: io::Error| Diagnostic::error(format!("I/O error: {}", e), None);
+        let mut out = String::new();
+        if let Some(n) = breakpoint {
+            out += &format!("Breakpoint {}, ", n + 1);
+        }
+        let (func, _) = interp.backtrace()[0];
+        out += &format!("{} at {}\n", func, self.place(at));
+        let loc = self.source_map.lookup(at.lo);
+        out += &format!("{}\t{}\n", loc.line, self.source_map.line_text(at.lo));
+        loop {
+            if self.interactive {
+                out += "(sysy-db) ";
+            }
+            let output = interp.output();
+            output.write_all(out.as_bytes()).map_err(io_error)?;
+            output.flush().map_err(io_error)?;
+            out.clear();
+            let mut line = String::new();
+            if interp.input().read_line(&mut line).map_err(io_error)? == 0 {
+                return Err(Diagnostic::error(
+                    "the program was killed by the debugger",
+                    None,
+                ));
+            }
+            let mut line = line.trim().to_string();
+            if line.is_empty() {
+                line = self.last_command.clone();
+                if line.is_empty() {
+                    continue;
+                }
+            }
+            if line == "quit" || line == "q" {
+                return Err(Diagnostic::error(
+                    "the program was killed by the debugger",
+                    None,
+                ));
+            }
+            let mode = self.command(interp, &line, &mut out);
+            self.last_command = line;
+            if let Some(mode) = mode {
+                self.mode = mode;
+                let output = interp.output();
+                output.write_all(out.as_bytes()).map_err(io_error)?;
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use std::cell::RefCell;
+    use std::io::Write;
+    use std::rc::Rc;
+
+    const SRC: &str = "int twice(int a) {
+    int b = a * 2;
+    return b;
+}
+int main() {
+    int x = 1;
+    x = twice(x);
+    x = x + 1;
+    return x;
+}
+";
+
+    /// Output the test can still read once the interpreter is done with it.
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn debug(commands: &str) -> (Result<i32, String>, String) {
+        debug_src(SRC, commands)
+    }
+
+    /// Debugs `src` with `commands` as input, returning the exit code or
+    /// error message and everything the debugger printed.
+    fn debug_src(src: &str, commands: &str) -> (Result<i32, String>, String) {
+        let mut source_map = SourceMap::default();
+        let base = source_map.add("t.c", src);
+        let units = [crate::sysy::CompUnitParser::new()
+            .parse(Lexer::new(src, base))
+            .unwrap()];
+        let output = Shared::default();
+        let result = {
+            let mut interp = Interpreter::new(
+                &units,
+                Box::new(commands.as_bytes()),
+                Box::new(output.clone()),
+            )
+            .unwrap();
+            let mut debugger = Debugger::new(&source_map, "t.c");
+            debugger.interactive = false;
+            interp.set_hook(Box::new(debugger));
+            interp.run_main().map_err(|err| err.message)
+        };
+        let text = String::from_utf8(output.0.take()).unwrap();
+        (result, text)
+    }
+
+    #[test]
+    fn stops_at_the_first_statement_of_main() {
+        let (result, out) = debug("c\n");
+        assert_eq!(result, Ok(3));
+        assert_eq!(out, "main at t.c:6\n6\t    int x = 1;\n");
+    }
+
+    #[test]
+    fn step_enters_calls_and_next_steps_over_them() {
+        let (_, out) = debug("n\ns\np a\nbt\nfinish\np x\nc\n");
+        assert_eq!(
+            out,
+            "main at t.c:6\n6\t    int x = 1;\n\
+             main at t.c:7\n7\t    x = twice(x);\n\
+             twice at t.c:2\n2\t    int b = a * 2;\n\
+             a = 1\n\
+             #0  twice at t.c:2\n#1  main at t.c:7\n\
+             main at t.c:8\n8\t    x = x + 1;\n\
+             x = 2\n"
+        );
+        let (_, out) = debug("n\nn\nn\nc\n");
+        assert!(!out.contains("twice at"), "{}", out);
+        assert!(out.contains("main at t.c:8"), "{}", out);
+    }
+
+    #[test]
+    fn breakpoints_stop_each_time_their_line_is_reached() {
+        let (result, out) = debug("b 3\nc\np b\nd 1\nc\n");
+        assert_eq!(result, Ok(3));
+        assert!(out.contains("Breakpoint 1 at t.c:3\n"), "{}", out);
+        assert!(
+            out.contains("Breakpoint 1, twice at t.c:3\n3\t    return b;\nb = 2\n"),
+            "{}",
+            out
+        );
+        assert!(out.contains("Deleted breakpoint 1\n"), "{}", out);
+    }
+
+    #[test]
+    fn quitting_kills_the_program() {
+        let (result, _) = debug("q\n");
+        assert_eq!(
+            result,
+            Err("the program was killed by the debugger".to_string())
+        );
+        let (result, _) = debug("");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn bodies_of_if_and_while_are_statements_to_stop_at() {
+        let src = "int main() {
+    int i = 0;
+    while (i < 2)
+        i = i + 1;
+    if (i)
+        return i;
+    return 0;
+}
+";
+        let (result, out) = debug_src(src, "s\ns\ns\ns\ns\ns\n");
+        assert_eq!(result, Ok(2));
+        let lines: Vec<&str> = out
+            .lines()
+            .filter_map(|line| line.strip_prefix("main at t.c:"))
+            .collect();
+        // The loop body is reached once per iteration.
+        assert_eq!(lines, ["2", "3", "4", "4", "5", "6"]);
+    }
+}
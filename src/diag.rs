@@ -0,0 +1,442 @@
+use crate::lexer::Tok;
+use crate::lint::Lint;
+use lalrpop_util::ParseError;
+use serde_json::{json, Value};
+use std::cell::RefCell;
+
+thread_local! {
+    // Per thread, so that compilations on different threads, such as tests,
+    // do not collect each other's diagnostics.
+    static DIAGNOSTICS: RefCell<Vec<Diagnostic>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Records a diagnostic found after parsing. Passes keep going after an
+/// error so that one run reports as much as possible; the driver collects
+/// everything with `take` once a pass is done.
+pub fn emit(diag: Diagnostic) {
+    DIAGNOSTICS.with(|diags| diags.borrow_mut().push(diag));
+}
+
+pub fn take() -> Vec<Diagnostic> {
+    DIAGNOSTICS.with(|diags| diags.take())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub lo: usize,
+    pub hi: usize,
+}
+
+impl Span {
+    pub fn new(lo: usize, hi: usize) -> Self {
+        Span { lo, hi }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl Severity {
+    pub fn name(self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        }
+    }
+}
+
+/// How diagnostics are printed, chosen with `--diagnostic-format`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Human,
+    /// One JSON object per line and diagnostic.
+    Json,
+    /// A single SARIF 2.1.0 log of every diagnostic.
+    Sarif,
+}
+
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// The catalog entry explaining the error, see `error_codes`.
+    pub code: Option<&'static str>,
+    /// The lint a warning comes from, named by its `-W` option.
+    pub lint: Option<Lint>,
+    pub message: String,
+    pub span: Option<Span>,
+    pub notes: Vec<String>,
+    /// Other places the diagnostic refers to, such as an earlier
+    /// definition, each with what it is.
+    pub related: Vec<(Span, String)>,
+}
+
+impl Diagnostic {
+    pub fn error(message: impl Into<String>, span: Option<Span>) -> Self {
+        Diagnostic {
+            severity: Severity::Error,
+            code: None,
+            lint: None,
+            message: message.into(),
+            span,
+            notes: Vec::new(),
+            related: Vec::new(),
+        }
+    }
+    pub fn warning(message: impl Into<String>, span: Option<Span>) -> Self {
+        Diagnostic {
+            severity: Severity::Warning,
+            ..Diagnostic::error(message, span)
+        }
+    }
+    pub fn with_code(mut self, code: &'static str) -> Self {
+        self.code = Some(code);
+        self
+    }
+    pub fn with_lint(mut self, lint: Lint) -> Self {
+        self.lint = Some(lint);
+        self
+    }
+    /// What machine-readable output identifies the diagnostic by: its code,
+    /// or for a warning without one the option controlling it.
+    pub fn rule(&self) -> Option<&'static str> {
+        self.code.or(self.lint.map(Lint::option))
+    }
+    pub fn with_note(mut self, note: impl Into<String>) -> Self {
+        self.notes.push(note.into());
+        self
+    }
+    pub fn with_related(mut self, span: Span, message: impl Into<String>) -> Self {
+        self.related.push((span, message.into()));
+        self
+    }
+}
+
+impl From<ParseError<usize, Tok, Diagnostic>> for Diagnostic {
+    fn from(err: ParseError<usize, Tok, Diagnostic>) -> Self {
+        match err {
+            ParseError::InvalidToken { location } => {
+                Diagnostic::error("invalid token", Some(Span::new(location, location)))
+                    .with_code("E0011")
+            }
+            ParseError::UnrecognizedEOF { location, expected } => Diagnostic::error(
+                format!("unexpected end of file, {}", expected_list(&expected)),
+                Some(Span::new(location, location)),
+            )
+            .with_code("E0011"),
+            ParseError::UnrecognizedToken {
+                token: (lo, tok, hi),
+                expected,
+            } => Diagnostic::error(
+                format!("unexpected `{}`, {}", tok, expected_list(&expected)),
+                Some(Span::new(lo, hi)),
+            )
+            .with_code("E0011"),
+            ParseError::ExtraToken {
+                token: (lo, tok, hi),
+            } => Diagnostic::error(
+                format!("unexpected `{}` after the end of the program", tok),
+                Some(Span::new(lo, hi)),
+            )
+            .with_code("E0011"),
+            ParseError::User { error } => error,
+        }
+    }
+}
+
+fn expected_list(expected: &[String]) -> String {
+    let names: Vec<String> = expected
+        .iter()
+        .map(|e| e.trim_matches('"').replace("\\\"", "\""))
+        .map(|e| format!("`{}`", e))
+        .collect();
+    match names.len() {
+        0 => "no token expected here".to_string(),
+        1 => format!("expected {}", names[0]),
+        _ => format!("expected one of {}", names.join(", ")),
+    }
+}
+
+struct SourceFile {
+    name: String,
+    text: String,
+    base: usize,
+    line_starts: Vec<usize>,
+    /// `(physical line, file, line)` for each `# <line> "<file>"` marker.
+    markers: Vec<(usize, String, usize)>,
+}
+
+pub struct Location<'a> {
+    pub file: &'a str,
+    pub line: usize,
+    pub col: usize,
+}
+
+/// Maps positions back to files, lines and columns. Every file added to the
+/// map occupies its own range of positions, so a `Span` identifies the file
+/// it points into. Line markers left by the preprocessor are honoured.
+#[derive(Default)]
+pub struct SourceMap {
+    files: Vec<SourceFile>,
+}
+
+impl SourceMap {
+    /// Registers `text` and returns the position of its first byte.
+    pub fn add(&mut self, name: &str, text: &str) -> usize {
+        let base = match self.files.last() {
+            Some(f) => f.base + f.text.len() + 1,
+            None => 0,
+        };
+        let mut line_starts = vec![0];
+        for (i, b) in text.bytes().enumerate() {
+            if b == b'\n' {
+                line_starts.push(i + 1);
+            }
+        }
+        let mut markers = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            if let Some((line_no, file)) = parse_line_marker(line) {
+                markers.push((i, file, line_no));
+            }
+        }
+        self.files.push(SourceFile {
+            name: name.to_string(),
+            text: text.to_string(),
+            base,
+            line_starts,
+            markers,
+        });
+        base
+    }
+
+    fn file(&self, pos: usize) -> &SourceFile {
+        let idx = self.files.partition_point(|f| f.base <= pos);
+        &self.files[idx - 1]
+    }
+
+    fn physical_line(file: &SourceFile, pos: usize) -> usize {
+        match file.line_starts.binary_search(&(pos - file.base)) {
+            Ok(l) => l,
+            Err(l) => l - 1,
+        }
+    }
+
+    pub fn lookup(&self, pos: usize) -> Location<'_> {
+        let file = self.file(pos);
+        let line = Self::physical_line(file, pos);
+        let col = pos - file.base - file.line_starts[line] + 1;
+        let marker = file.markers.iter().rev().find(|(idx, _, _)| *idx < line);
+        match marker {
+            Some((idx, name, line_no)) => Location {
+                file: name,
+                line: line_no + line - idx - 1,
+                col,
+            },
+            None => Location {
+                file: &file.name,
+                line: line + 1,
+                col,
+            },
+        }
+    }
+
+    /// The text of the line `pos` is on.
+    pub fn line_text(&self, pos: usize) -> &str {
+        let file = self.file(pos);
+        let line = Self::physical_line(file, pos);
+        let lo = file.line_starts[line];
+        let hi = file
+            .line_starts
+            .get(line + 1)
+            .copied()
+            .unwrap_or(file.text.len());
+        file.text[lo..hi].trim_end_matches(['\n', '\r'])
+    }
+
+    pub fn render(&self, diag: &Diagnostic) -> String {
+        let level = diag.severity.name();
+        let mut out = match diag.code {
+            Some(code) => format!("{}[{}]: {}", level, code, diag.message),
+            None => format!("{}: {}", level, diag.message),
+        };
+        if let Some(lint) = diag.lint {
+            out += &format!(" [{}]", lint.option());
+        }
+        out.push('\n');
+        match diag.span {
+            Some(span) => {
+                let (gutter, excerpt) = self.excerpt(span);
+                out += &excerpt;
+                for note in diag.notes.iter() {
+                    out += &format!("{} = note: {}\n", gutter, note);
+                }
+            }
+            None => {
+                for note in diag.notes.iter() {
+                    out += &format!("  = note: {}\n", note);
+                }
+            }
+        }
+        for (span, message) in diag.related.iter() {
+            out += &format!("note: {}\n", message);
+            out += &self.excerpt(*span).1;
+        }
+        out
+    }
+
+    /// The location and source line of a span with the span underlined,
+    /// and the blank gutter the lines after it are indented by.
+    fn excerpt(&self, span: Span) -> (String, String) {
+        let loc = self.lookup(span.lo);
+        let gutter = " ".repeat(loc.line.to_string().len());
+        let text = self.line_text(span.lo);
+        let width = if span.hi > span.lo && span.hi - span.lo <= text.len() + 1 - loc.col {
+            span.hi - span.lo
+        } else if span.hi > span.lo {
+            text.len().saturating_sub(loc.col - 1).max(1)
+        } else {
+            1
+        };
+        let mut out = format!("{}--> {}:{}:{}\n", gutter, loc.file, loc.line, loc.col);
+        out += &format!("{} |\n", gutter);
+        out += &format!("{} | {}\n", loc.line, text);
+        out += &format!(
+            "{} | {}{}\n",
+            gutter,
+            " ".repeat(loc.col - 1),
+            "^".repeat(width)
+        );
+        (gutter, out)
+    }
+
+    /// The file and the 1-based start and end of a span.
+    fn region(&self, span: Span) -> (String, usize, usize, usize, usize) {
+        let start = self.lookup(span.lo);
+        let end = self.lookup(span.hi.max(span.lo));
+        (
+            start.file.to_string(),
+            start.line,
+            start.col,
+            end.line,
+            end.col,
+        )
+    }
+
+    fn span_json(&self, span: Span) -> Value {
+        let (file, line, column, end_line, end_column) = self.region(span);
+        json!({
+            "file": file,
+            "line": line,
+            "column": column,
+            "end_line": end_line,
+            "end_column": end_column,
+        })
+    }
+
+    pub fn to_json(&self, diag: &Diagnostic) -> Value {
+        let related: Vec<Value> = diag
+            .related
+            .iter()
+            .map(|(span, message)| json!({ "message": message, "span": self.span_json(*span) }))
+            .collect();
+        json!({
+            "code": diag.rule(),
+            "severity": diag.severity.name(),
+            "message": diag.message,
+            "span": diag.span.map(|span| self.span_json(span)),
+            "notes": diag.notes,
+            "related": related,
+        })
+    }
+
+    fn sarif_location(&self, span: Span) -> Value {
+        let (file, line, column, end_line, end_column) = self.region(span);
+        json!({
+            "physicalLocation": {
+                "artifactLocation": { "uri": file },
+                "region": {
+                    "startLine": line,
+                    "startColumn": column,
+                    "endLine": end_line,
+                    "endColumn": end_column,
+                },
+            },
+        })
+    }
+
+    pub fn to_sarif(&self, diags: &[Diagnostic]) -> Value {
+        let mut rules: Vec<&str> = diags.iter().filter_map(|d| d.rule()).collect();
+        rules.sort_unstable();
+        rules.dedup();
+        let rules: Vec<Value> = rules
+            .into_iter()
+            .map(|rule| {
+                let summary = match Lint::from_option(rule) {
+                    Some(lint) => lint.summary().to_string(),
+                    // The first paragraph, which the markdown wraps.
+                    None => crate::error_codes::explain(rule)
+                        .unwrap_or_default()
+                        .lines()
+                        .take_while(|line| !line.trim().is_empty())
+                        .collect::<Vec<_>>()
+                        .join(" "),
+                };
+                json!({ "id": rule, "shortDescription": { "text": summary } })
+            })
+            .collect();
+        let results: Vec<Value> = diags
+            .iter()
+            .map(|diag| {
+                let mut result = json!({
+                    "level": diag.severity.name(),
+                    "message": { "text": diag.message },
+                    "properties": { "notes": diag.notes },
+                });
+                if let Some(rule) = diag.rule() {
+                    result["ruleId"] = json!(rule);
+                }
+                if let Some(span) = diag.span {
+                    result["locations"] = json!([self.sarif_location(span)]);
+                }
+                if !diag.related.is_empty() {
+                    let related: Vec<Value> = diag
+                        .related
+                        .iter()
+                        .map(|(span, message)| {
+                            let mut location = self.sarif_location(*span);
+                            location["message"] = json!({ "text": message });
+                            location
+                        })
+                        .collect();
+                    result["relatedLocations"] = json!(related);
+                }
+                result
+            })
+            .collect();
+        json!({
+            "$schema": "https://json.schemastore.org/sarif-2.1.0.json",
+            "version": "2.1.0",
+            "runs": [{
+                "tool": {
+                    "driver": {
+                        "name": env!("CARGO_PKG_NAME"),
+                        "version": env!("CARGO_PKG_VERSION"),
+                        "rules": rules,
+                    },
+                },
+                "results": results,
+            }],
+        })
+    }
+}
+
+/// Parses a `# <line> "<file>"` line marker as emitted by the preprocessor.
+pub fn parse_line_marker(line: &str) -> Option<(usize, String)> {
+    let rest = line.strip_prefix('#')?.trim_start();
+    let (num, rest) = rest.split_once(' ')?;
+    let line_no = num.parse().ok()?;
+    let file = rest.trim().strip_prefix('"')?.strip_suffix('"')?;
+    Some((line_no, file.to_string()))
+}
@@ -0,0 +1,37 @@
+/// Long explanations of the error codes attached to diagnostics, printed by
+/// `--explain`. Codes are never reused: once published, an entry keeps its
+/// number even if the diagnostic is reworded.
+const CATALOG: [(&str, &str); 18] = [
+    ("E0001", include_str!("error_codes/E0001.md")),
+    ("E0002", include_str!("error_codes/E0002.md")),
+    ("E0003", include_str!("error_codes/E0003.md")),
+    ("E0004", include_str!("error_codes/E0004.md")),
+    ("E0005", include_str!("error_codes/E0005.md")),
+    ("E0006", include_str!("error_codes/E0006.md")),
+    ("E0007", include_str!("error_codes/E0007.md")),
+    ("E0008", include_str!("error_codes/E0008.md")),
+    ("E0009", include_str!("error_codes/E0009.md")),
+    ("E0010", include_str!("error_codes/E0010.md")),
+    ("E0011", include_str!("error_codes/E0011.md")),
+    ("E0012", include_str!("error_codes/E0012.md")),
+    ("E0013", include_str!("error_codes/E0013.md")),
+    ("E0014", include_str!("error_codes/E0014.md")),
+    ("E0015", include_str!("error_codes/E0015.md")),
+    ("E0016", include_str!("error_codes/E0016.md")),
+    ("E0017", include_str!("error_codes/E0017.md")),
+    ("E0018", include_str!("error_codes/E0018.md")),
+];
+
+/// The explanation of `code`, which may be given as `E0004` or `0004`.
+pub fn explain(code: &str) -> Option<&'static str> {
+    let code = code.to_ascii_uppercase();
+    let code = if code.starts_with('E') {
+        code
+    } else {
+        format!("E{}", code)
+    };
+    CATALOG
+        .iter()
+        .find(|(c, _)| *c == code)
+        .map(|(_, text)| *text)
+}
@@ -0,0 +1,667 @@
+use crate::ast::*;
+use crate::diag::{Diagnostic, Span};
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// An upper bound on the interpreter's own stack use per nested call. A
+/// recursive call in a block in an `if` in a `while` measured about 5 KiB in
+/// an optimized build and 50 KiB in a debug build.
+const CALL_STACK_USE: usize = if cfg!(debug_assertions) {
+    64 << 10
+} else {
+    8 << 10
+};
+
+/// The most stack the interpreter may ask for: 4 GiB, or a quarter of the
+/// address space on 32-bit hosts.
+const MAX_STACK_SIZE: u64 = if usize::BITS < 64 { 1 << 30 } else { 4 << 30 };
+
+/// Calls nested deeper than this are reported as a stack overflow instead of
+/// overflowing the interpreter's own stack.
+pub const MAX_CALL_DEPTH: usize = {
+    let fits = (MAX_STACK_SIZE / CALL_STACK_USE as u64) as usize;
+    if fits < 100_000 {
+        fits
+    } else {
+        100_000
+    }
+};
+
+/// The stack the interpreter needs to run on: room for `MAX_CALL_DEPTH`
+/// calls, and a megabyte for everything around them.
+pub const STACK_SIZE: usize = MAX_CALL_DEPTH * CALL_STACK_USE + (1 << 20);
+
+/// Runtime errors list at most this many of the innermost calls.
+const MAX_NOTED_CALLS: usize = 16;
+
+/// How a statement finished.
+enum Flow {
+    Next,
+    Break,
+    Continue,
+    Return(i32),
+}
+
+struct Frame {
+    func: String,
+    /// Innermost scope last. A function's parameters and the outermost
+    /// block of its body share a scope, as in `ast2ir`.
+    scopes: Vec<HashMap<String, i32>>,
+    /// The statement or declaration running in this call.
+    at: Option<Span>,
+}
+
+/// Watches execution, for a debugger. `before` runs ahead of every
+/// statement and declaration in a block; returning an error ends the
+/// program with it.
+pub trait Hook {
+    fn before(&mut self, interp: &mut Interpreter<'_>, at: Span) -> Result<(), Diagnostic>;
+}
+
+/// Executes checked compilation units directly, as a reference for what the
+/// generated Koopa and RISC-V should do. Arithmetic wraps like the hardware;
+/// division by zero is a runtime error; uninitialized locals read as 0; an
+/// `int` function falling off its end returns 0.
+pub struct Interpreter<'a> {
+    /// Counted references, so that a call can hold on to its function while
+    /// it runs.
+    funcs: HashMap<String, Rc<FuncDef>>,
+    globals: HashMap<String, i32>,
+    frames: Vec<Frame>,
+    input: Box<dyn BufRead + 'a>,
+    output: Box<dyn Write + 'a>,
+    /// Start and line of the running `starttime()`.
+    timer: Option<(Instant, i32)>,
+    timed: Option<Duration>,
+    hook: Option<Box<dyn Hook + 'a>>,
+}
+
+fn format_duration(d: Duration) -> String {
+    let us = d.as_micros();
+    format!(
+        "{}H-{}M-{}S-{}us",
+        us / 3_600_000_000,
+        us / 60_000_000 % 60,
+        us / 1_000_000 % 60,
+        us % 1_000_000
+    )
+}
+
+impl<'a> Interpreter<'a> {
+    /// Loads the functions and evaluates the globals of every unit.
+    pub fn new(
+        units: &[CompUnit],
+        input: Box<dyn BufRead + 'a>,
+        output: Box<dyn Write + 'a>,
+    ) -> Result<Self, Diagnostic> {
+        let mut interp = Interpreter {
+            funcs: HashMap::new(),
+            globals: HashMap::new(),
+            frames: Vec::new(),
+            input,
+            output,
+            timer: None,
+            timed: None,
+            hook: None,
+        };
+        for unit in units.iter() {
+            for item in unit.list.iter() {
+                interp.global(item)?;
+            }
+        }
+        Ok(interp)
+    }
+
+    /// Adds a top-level declaration; later definitions replace earlier ones.
+    /// The interpreter keeps a copy of function definitions.
+    pub fn global(&mut self, item: &CompItem) -> Result<(), Diagnostic> {
+        match item {
+            CompItem::FuncDef(func) => {
+                self.funcs.insert(func.ident.clone(), Rc::new(func.clone()));
+            }
+            CompItem::FuncDecl(_) => {}
+            CompItem::ConstDecl(defs) => {
+                for def in defs.iter() {
+                    let value = self.expr(&def.value)?;
+                    self.globals.insert(def.id.clone(), value);
+                }
+            }
+            CompItem::VarDecl(defs) => {
+                for def in defs.iter() {
+                    let value = match &def.value {
+                        Some(e) => self.expr(e)?,
+                        None => 0,
+                    };
+                    self.globals.insert(def.id.clone(), value);
+                }
+            }
+            CompItem::ExternDecl(defs) => {
+                for def in defs.iter() {
+                    self.globals.entry(def.id.clone()).or_insert(0);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The program's input, for a front end that shares it with the program.
+    pub fn input(&mut self) -> &mut (dyn BufRead + 'a) {
+        &mut *self.input
+    }
+
+    /// The program's output, for a front end that shares it with the program.
+    pub fn output(&mut self) -> &mut (dyn Write + 'a) {
+        &mut *self.output
+    }
+
+    pub fn set_hook(&mut self, hook: Box<dyn Hook + 'a>) {
+        self.hook = Some(hook);
+    }
+
+    /// How many calls are running.
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// The running calls, innermost first, with the statement each is at.
+    pub fn backtrace(&self) -> Vec<(&str, Option<Span>)> {
+        self.frames
+            .iter()
+            .rev()
+            .map(|frame| (frame.func.as_str(), frame.at))
+            .collect()
+    }
+
+    /// The value of a variable or constant as seen from the running
+    /// statement.
+    pub fn value(&self, name: &str) -> Option<i32> {
+        self.lookup(name)
+    }
+
+    /// The locals visible from the running statement, innermost scope first.
+    /// Shadowed names are left out.
+    pub fn locals(&self) -> Vec<(&str, i32)> {
+        let mut out: Vec<(&str, i32)> = Vec::new();
+        for scope in self
+            .frames
+            .iter()
+            .last()
+            .into_iter()
+            .flat_map(|f| f.scopes.iter().rev())
+        {
+            let mut names: Vec<(&str, i32)> = scope
+                .iter()
+                .filter(|(name, _)| !out.iter().any(|(n, _)| n == name))
+                .map(|(name, value)| (name.as_str(), *value))
+                .collect();
+            names.sort();
+            out.extend(names);
+        }
+        out
+    }
+
+    /// Every global, by name.
+    pub fn globals(&self) -> Vec<(&str, i32)> {
+        let mut out: Vec<(&str, i32)> = self
+            .globals
+            .iter()
+            .map(|(name, value)| (name.as_str(), *value))
+            .collect();
+        out.sort();
+        out
+    }
+
+    /// Runs `main` and returns its result.
+    pub fn run_main(&mut self) -> Result<i32, Diagnostic> {
+        let result = self.call("main", Vec::new(), None);
+        self.output.flush().ok();
+        if let Some(total) = self.timed {
+            eprintln!("TOTAL: {}", format_duration(total));
+        }
+        result
+    }
+
+    fn error(&self, message: String, span: Option<Span>) -> Diagnostic {
+        let mut err = Diagnostic::error(format!("runtime error: {}", message), span);
+        for (i, frame) in self.frames.iter().rev().take(MAX_NOTED_CALLS).enumerate() {
+            err = err.with_note(match i {
+                0 => format!("in `{}`", frame.func),
+                _ => format!("called from `{}`", frame.func),
+            });
+        }
+        if self.frames.len() > MAX_NOTED_CALLS {
+            err = err.with_note(format!(
+                "and {} more calls",
+                self.frames.len() - MAX_NOTED_CALLS
+            ));
+        }
+        err
+    }
+
+    /// Checks that `name` was called with one argument per parameter. The
+    /// compiler rejects other calls, but `call` can be given anything.
+    fn check_arity(
+        &self,
+        name: &str,
+        params: usize,
+        args: usize,
+        span: Option<Span>,
+    ) -> Result<(), Diagnostic> {
+        if params == args {
+            return Ok(());
+        }
+        Err(self.error(
+            format!(
+                "`{}` takes {} argument{} but {} {} given",
+                name,
+                params,
+                if params == 1 { "" } else { "s" },
+                args,
+                if args == 1 { "was" } else { "were" }
+            ),
+            span,
+        ))
+    }
+
+    fn lookup(&self, name: &str) -> Option<i32> {
+        self.frames
+            .last()
+            .and_then(|f| f.scopes.iter().rev().find_map(|s| s.get(name)))
+            .or_else(|| self.globals.get(name))
+            .copied()
+    }
+
+    fn lookup_mut(&mut self, name: &str) -> Option<&mut i32> {
+        let local = self
+            .frames
+            .last_mut()
+            .and_then(|f| f.scopes.iter_mut().rev().find_map(|s| s.get_mut(name)));
+        match local {
+            Some(slot) => Some(slot),
+            None => self.globals.get_mut(name),
+        }
+    }
+
+    fn declare(&mut self, name: &str, value: i32) {
+        let frame = self.frames.last_mut().unwrap();
+        let scope = frame.scopes.last_mut().unwrap();
+        scope.insert(name.to_string(), value);
+    }
+
+    /// Calls a function for its value.
+    pub fn call(
+        &mut self,
+        name: &str,
+        args: Vec<i32>,
+        span: Option<Span>,
+    ) -> Result<i32, Diagnostic> {
+        match self.invoke(name, args, span)? {
+            Some(value) => Ok(value),
+            None => Err(self
+                .error(format!("`{}` does not return a value", name), span)
+                .with_code("E0017")),
+        }
+    }
+
+    /// Calls a function. Returns its value, or `None` for a `void`
+    /// function.
+    fn invoke(
+        &mut self,
+        name: &str,
+        args: Vec<i32>,
+        span: Option<Span>,
+    ) -> Result<Option<i32>, Diagnostic> {
+        let Some(func) = self.funcs.get(name).cloned() else {
+            return self.builtin(name, &args, span);
+        };
+        if self.frames.len() >= MAX_CALL_DEPTH {
+            return Err(self.error(
+                format!("stack overflow: more than {} nested calls", MAX_CALL_DEPTH),
+                span,
+            ));
+        }
+        self.check_arity(name, func.params.len(), args.len(), span)?;
+        let params = func
+            .params
+            .iter()
+            .map(|p| p.ident.clone())
+            .zip(args)
+            .collect();
+        self.frames.push(Frame {
+            func: name.to_string(),
+            scopes: vec![params],
+            at: None,
+        });
+        let flow = self.block(&func.block, false);
+        self.frames.pop();
+        let value = match flow? {
+            Flow::Return(value) => value,
+            // Running off the end of an `int` function returns 0, as in
+            // the compiled code.
+            _ => 0,
+        };
+        Ok(matches!(func.func_type, FuncType::Int).then_some(value))
+    }
+
+    fn builtin(
+        &mut self,
+        name: &str,
+        args: &[i32],
+        span: Option<Span>,
+    ) -> Result<Option<i32>, Diagnostic> {
+        let io_error = |e: std::io::Error| Diagnostic::error(format!("I/O error: {}", e), span);
+        let params = match name {
+            "getint" | "getch" => 0,
+            "putint" | "putch" | "_sysy_starttime" | "_sysy_stoptime" => 1,
+            _ => args.len(),
+        };
+        self.check_arity(name, params, args.len(), span)?;
+        match name {
+            "getint" => {
+                let mut text = String::new();
+                loop {
+                    let buf = self.input.fill_buf().map_err(io_error)?;
+                    let Some(&c) = buf.first() else { break };
+                    let sign = text.is_empty() && (c == b'-' || c == b'+');
+                    if c.is_ascii_digit() || sign {
+                        text.push(c as char);
+                    } else if !text.is_empty() {
+                        break;
+                    } else if !c.is_ascii_whitespace() {
+                        return Err(self.error(
+                            format!("`getint` expected an integer, found `{}`", c.escape_ascii()),
+                            span,
+                        ));
+                    }
+                    self.input.consume(1);
+                }
+                let digits = text.trim_start_matches(['-', '+']);
+                let magnitude = digits.bytes().fold(0i32, |v, d| {
+                    v.wrapping_mul(10).wrapping_add((d - b'0') as i32)
+                });
+                Ok(Some(if text.starts_with('-') {
+                    magnitude.wrapping_neg()
+                } else {
+                    magnitude
+                }))
+            }
+            "getch" => {
+                let buf = self.input.fill_buf().map_err(io_error)?;
+                match buf.first().copied() {
+                    Some(c) => {
+                        self.input.consume(1);
+                        Ok(Some(c as i32))
+                    }
+                    None => Ok(Some(-1)),
+                }
+            }
+            "putint" => {
+                write!(self.output, "{}", args[0]).map_err(io_error)?;
+                Ok(None)
+            }
+            "putch" => {
+                self.output.write_all(&[args[0] as u8]).map_err(io_error)?;
+                Ok(None)
+            }
+            "_sysy_starttime" => {
+                self.timer = Some((Instant::now(), args[0]));
+                Ok(None)
+            }
+            "_sysy_stoptime" => {
+                if let Some((start, line)) = self.timer.take() {
+                    let elapsed = start.elapsed();
+                    eprintln!(
+                        "Timer@{:04}-{:04}: {}",
+                        line,
+                        args[0],
+                        format_duration(elapsed)
+                    );
+                    *self.timed.get_or_insert(Duration::ZERO) += elapsed;
+                }
+                Ok(None)
+            }
+            "getarray" | "putarray" => Err(self.error(
+                format!(
+                    "`{}` needs arrays, which the interpreter does not support",
+                    name
+                ),
+                span,
+            )),
+            _ => Err(self.error(
+                format!(
+                    "`{}` is an external function with no definition to run",
+                    name
+                ),
+                span,
+            )),
+        }
+    }
+
+    fn block(&mut self, block: &Block, new_scope: bool) -> Result<Flow, Diagnostic> {
+        if new_scope {
+            self.frames.last_mut().unwrap().scopes.push(HashMap::new());
+        }
+        let mut flow = Ok(Flow::Next);
+        for item in block.items.iter() {
+            flow = self.item(item);
+            if !matches!(flow, Ok(Flow::Next)) {
+                break;
+            }
+        }
+        if new_scope {
+            self.frames.last_mut().unwrap().scopes.pop();
+        }
+        flow
+    }
+
+    fn item(&mut self, item: &BlockItem) -> Result<Flow, Diagnostic> {
+        let at = match item {
+            BlockItem::ConstDecl(defs) => defs.first().map(|def| def.span),
+            BlockItem::VarDecl(defs) => defs.first().map(|def| def.span),
+            BlockItem::Stmt(_, span) => Some(*span),
+        };
+        if let Some(at) = at {
+            self.reach(at)?;
+        }
+        match item {
+            BlockItem::ConstDecl(defs) => {
+                for def in defs.iter() {
+                    let value = self.expr(&def.value)?;
+                    self.declare(&def.id, value);
+                }
+            }
+            BlockItem::VarDecl(defs) => {
+                for def in defs.iter() {
+                    let value = match &def.value {
+                        Some(e) => self.expr(e)?,
+                        None => 0,
+                    };
+                    self.declare(&def.id, value);
+                }
+            }
+            BlockItem::Stmt(stmt, _) => return self.stmt(stmt),
+        }
+        Ok(Flow::Next)
+    }
+
+    /// Records that the statement at `at` is about to run, and gives the
+    /// hook its chance to stop there.
+    fn reach(&mut self, at: Span) -> Result<(), Diagnostic> {
+        self.frames.last_mut().unwrap().at = Some(at);
+        if let Some(mut hook) = self.hook.take() {
+            let result = hook.before(self, at);
+            self.hook = Some(hook);
+            result?;
+        }
+        Ok(())
+    }
+
+    /// Runs a branch of an `if` or the body of a `while`. Unless it is a
+    /// block, whose items are reached one by one, it is a statement of its
+    /// own to stop at.
+    fn body(&mut self, (stmt, at): &(Stmt, Span)) -> Result<Flow, Diagnostic> {
+        if !matches!(stmt, Stmt::Block(_)) {
+            self.reach(*at)?;
+        }
+        self.stmt(stmt)
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) -> Result<Flow, Diagnostic> {
+        match stmt {
+            Stmt::Ret(e) => {
+                let value = match e {
+                    Some(e) => self.expr(e)?,
+                    None => 0,
+                };
+                Ok(Flow::Return(value))
+            }
+            Stmt::Assign(name, e, span) => {
+                let value = self.expr(e)?;
+                match self.lookup_mut(name) {
+                    Some(slot) => *slot = value,
+                    None => {
+                        return Err(self.error(format!("`{}` is not declared", name), Some(*span)))
+                    }
+                }
+                Ok(Flow::Next)
+            }
+            Stmt::Block(block) => self.block(block, true),
+            Stmt::Expr(e) => {
+                match e {
+                    // A call of its own may be of a `void` function.
+                    Some(Expr::Func(name, args, span)) => {
+                        let values = self.args(args)?;
+                        self.invoke(name, values, Some(*span))?;
+                    }
+                    Some(e) => {
+                        self.expr(e)?;
+                    }
+                    None => {}
+                }
+                Ok(Flow::Next)
+            }
+            Stmt::IfElse(cond, then, otherwise) => {
+                if self.expr(cond)? != 0 {
+                    self.body(then)
+                } else if let Some(otherwise) = otherwise {
+                    self.body(otherwise)
+                } else {
+                    Ok(Flow::Next)
+                }
+            }
+            Stmt::While(cond, body) => {
+                while self.expr(cond)? != 0 {
+                    match self.body(body)? {
+                        Flow::Break => break,
+                        Flow::Return(value) => return Ok(Flow::Return(value)),
+                        Flow::Next | Flow::Continue => {}
+                    }
+                }
+                Ok(Flow::Next)
+            }
+            Stmt::Break(_) => Ok(Flow::Break),
+            Stmt::Continue(_) => Ok(Flow::Continue),
+        }
+    }
+
+    pub fn expr(&mut self, expr: &Expr) -> Result<i32, Diagnostic> {
+        Ok(match expr {
+            Expr::Number(n) => *n,
+            Expr::UnaryExpr(op, e) => {
+                let value = self.expr(e)?;
+                match op {
+                    UnaryOp::Plus => value,
+                    UnaryOp::Minus => value.wrapping_neg(),
+                    UnaryOp::Not => (value == 0) as i32,
+                }
+            }
+            Expr::BinaryExpr(lhs, op, rhs, span) => {
+                let lhs = self.expr(lhs)?;
+                match (op, lhs) {
+                    (BinaryOp::And, 0) => return Ok(0),
+                    (BinaryOp::Or, v) if v != 0 => return Ok(1),
+                    _ => {}
+                }
+                let rhs = self.expr(rhs)?;
+                match op {
+                    BinaryOp::Plus => lhs.wrapping_add(rhs),
+                    BinaryOp::Minus => lhs.wrapping_sub(rhs),
+                    BinaryOp::Multiply => lhs.wrapping_mul(rhs),
+                    BinaryOp::Divide | BinaryOp::Modulo if rhs == 0 => {
+                        return Err(self.error("division by zero".to_string(), Some(*span)))
+                    }
+                    BinaryOp::Divide => lhs.wrapping_div(rhs),
+                    BinaryOp::Modulo => lhs.wrapping_rem(rhs),
+                    BinaryOp::Less => (lhs < rhs) as i32,
+                    BinaryOp::LessOrEqual => (lhs <= rhs) as i32,
+                    BinaryOp::Greater => (lhs > rhs) as i32,
+                    BinaryOp::GreaterOrEqual => (lhs >= rhs) as i32,
+                    BinaryOp::Eq => (lhs == rhs) as i32,
+                    BinaryOp::Neq => (lhs != rhs) as i32,
+                    BinaryOp::And | BinaryOp::Or => (rhs != 0) as i32,
+                }
+            }
+            Expr::LVal(name, span) => match self.lookup(name) {
+                Some(value) => value,
+                None => return Err(self.error(format!("`{}` is not declared", name), Some(*span))),
+            },
+            Expr::Func(name, args, span) => {
+                let values = self.args(args)?;
+                self.call(name, values, Some(*span))?
+            }
+        })
+    }
+
+    fn args(&mut self, args: &[Expr]) -> Result<Vec<i32>, Diagnostic> {
+        args.iter().map(|arg| self.expr(arg)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use std::thread;
+
+    #[test]
+    fn the_call_limit_fits_on_the_stack() {
+        let src = "int f(int n) {
+    int i = 0;
+    while (i < 1) {
+        if (n > 0) {
+            {
+                return f(n - 1) + 1;
+            }
+        }
+        i = i + 1;
+    }
+    return 0;
+}
+int main() { return f(1000000); }
+";
+        let units = [crate::sysy::CompUnitParser::new()
+            .parse(Lexer::new(src, 0))
+            .unwrap()];
+        let message = thread::scope(|scope| {
+            thread::Builder::new()
+                .stack_size(STACK_SIZE)
+                .spawn_scoped(scope, || {
+                    let mut interp =
+                        Interpreter::new(&units, Box::new(&b""[..]), Box::new(Vec::new())).unwrap();
+                    interp.run_main().unwrap_err().message
+                })
+                .unwrap()
+                .join()
+                .unwrap()
+        });
+        assert_eq!(
+            message,
+            format!(
+                "runtime error: stack overflow: more than {} nested calls",
+                MAX_CALL_DEPTH
+            )
+        );
+    }
+}
@@ -1,28 +1,87 @@
-use std::collections::HashMap;
+use crate::diag::Diagnostic;
+use std::collections::{HashMap, HashSet};
 
-pub fn ir2riscv(ir: String) -> String {
+/// Arguments passed in `a0` to `a7`; the rest go on the stack.
+const ARG_REGS: usize = 8;
+
+/// Translates one Koopa module. Globals named in `externs` are defined by
+/// another module and get no storage here. Globals not in `written`, which
+/// should name every global any module of the program stores to, are placed
+/// in `.rodata`. Globals in `symbols` get the assembly symbol it maps their
+/// Koopa name to; the others keep their Koopa name.
+pub fn ir2riscv(
+    ir: String,
+    externs: &HashSet<String>,
+    written: &HashSet<String>,
+    symbols: &HashMap<String, String>,
+) -> Result<String, Diagnostic> {
     let mut out = String::new();
-    let driver = koopa::front::Driver::from(ir);
-    let program = driver.generate_program().unwrap();
-    out += ".data\n";
+    // Pointers are a word on RV32.
+    koopa::ir::Type::set_ptr_size(4);
+    let mut program = parse_koopa(&ir)?;
+    let mut data = String::new();
+    let mut rodata = String::new();
+    let mut renamed = Vec::new();
     for &inst in program.inst_layout() {
-        println!("inst: {:?}", program.borrow_value(inst).kind());
         let name = &program.borrow_value(inst).name().clone().unwrap()[1..];
-        out += &format!(".globl {}\n", name);
-        out += &format!("{}:\n", name);
-        
+        let symbol = symbols.get(name).map_or(name, String::as_str);
+        renamed.push((inst, format!("@{}", symbol)));
+        if externs.contains(name) {
+            continue;
+        }
+        let section = match written.contains(name) {
+            true => &mut data,
+            false => &mut rodata,
+        };
+        *section += &format!(".globl {}\n", symbol);
+        *section += &format!("{}:\n", symbol);
+        let init = match program.borrow_value(inst).kind() {
+            koopa::ir::ValueKind::GlobalAlloc(alloc) => alloc.init(),
+            _ => unreachable!(),
+        };
+        let mut chunks = Vec::new();
+        flatten_init(&program, init, &mut chunks);
+        for chunk in chunks {
+            match chunk {
+                DataChunk::Words(words) => {
+                    for line in words.chunks(8) {
+                        let line: Vec<String> = line.iter().map(i32::to_string).collect();
+                        *section += &format!(".word {}\n", line.join(", "));
+                    }
+                }
+                DataChunk::Zero(bytes) => *section += &format!(".zero {}\n", bytes),
+            }
+        }
+    }
+    for (inst, symbol) in renamed {
+        program.set_value_name(inst, Some(symbol));
+    }
+    out += ".data\n";
+    out += &data;
+    if !rodata.is_empty() {
+        out += ".section .rodata\n";
+        out += &rodata;
     }
     for &func in program.func_layout() {
         let func_data = program.func(func);
         if func_data.layout().entry_bb().is_none() {
             continue;
         }
+        let start = out.len();
         out += ".text\n";
         out += &format!(".globl {}\n", &func_data.name()[1..]);
         out += &format!("{}:\n", &func_data.name()[1..]);
-        let mut stack_map: HashMap<koopa::ir::Value, String> = HashMap::new();
+        let mut stack_map: HashMap<koopa::ir::Value, i32> = HashMap::new();
         let mut stack_offset = -4;
-        let mut size = func_data.dfg().values().len() * 4 + 4;
+        // A word for `ra`, a scratch word for block arguments, one for each
+        // value and room for what is allocated.
+        let mut size = 8;
+        for data in func_data.dfg().values().values() {
+            size += match data.kind() {
+                koopa::ir::ValueKind::Alloc(_) => pointee_size(data.ty()),
+                _ => 4,
+            };
+        }
         let mut max_arg_num = 0;
         for (_, node) in func_data.layout().bbs() {
             for &inst in node.insts().keys() {
@@ -37,25 +96,38 @@ pub fn ir2riscv(ir: String) -> String {
                 }
             }
         }
-        size += max_arg_num * 4;
-        stack_offset += max_arg_num as i32 * 4;
+        // Arguments past the eighth go at the bottom of the frame.
+        let stack_args = max_arg_num.saturating_sub(ARG_REGS);
+        size += stack_args * 4;
+        stack_offset += stack_args as i32 * 4;
         if size % 16 != 0 {
             size += 16 - size % 16;
         }
-        out += &format!("addi sp, sp, -{}\n", size);
-        out += &format!("sw ra {}(sp)\n", size - 4);
-        let mut arg_pos = size;
-        for arg in func_data.params() {
-            stack_map.insert(*arg, format!("{}(sp)", arg_pos));
-            arg_pos += 4;
+        out += &adjust_sp(-(size as i32));
+        out += &stack_access("sw", "ra", size as i32 - 4);
+        for (i, arg) in func_data.params().iter().enumerate() {
+            if i < ARG_REGS {
+                stack_offset += 4;
+                out += &stack_access("sw", &format!("a{}", i), stack_offset);
+                stack_map.insert(*arg, stack_offset);
+            } else {
+                // Passed at the bottom of the caller's frame.
+                let offset = size + (i - ARG_REGS) * 4;
+                stack_map.insert(*arg, offset as i32);
+            }
+        }
+        // Every edge into a block copies its arguments to the slots of the
+        // block's parameters.
+        for (&bb, _) in func_data.layout().bbs() {
+            for &param in func_data.dfg().bb(bb).params() {
+                stack_offset += 4;
+                stack_map.insert(param, stack_offset);
+            }
         }
         for (&bb, node) in func_data.layout().bbs() {
             // println!("Block: {:?}", func_data.dfg().bb(bb).name());
             if func_data.dfg().bb(bb).name().as_ref().unwrap() != "%entry" {
-                out += &format!(
-                    "{}:\n",
-                    &func_data.dfg().bb(bb).name().as_ref().unwrap()[1..]
-                );
+                out += &format!("{}:\n", block_label(func_data, bb));
             }
             for &inst in node.insts().keys() {
                 let code = stmt2str(
@@ -70,8 +142,197 @@ pub fn ir2riscv(ir: String) -> String {
                 out += &code;
             }
         }
+        let code = out.split_off(start);
+        out += &relax_branches(&code, &func_data.name()[1..]);
         out += "\n";
     }
+    Ok(out)
+}
+
+/// Parses Koopa text generated by `ast2ir`. It always should: failing to
+/// is a bug in the front end, reported as an internal compiler error.
+pub fn parse_koopa(ir: &str) -> Result<koopa::ir::Program, Diagnostic> {
+    koopa::front::Driver::from(ir)
+        .generate_program()
+        .map_err(|err| {
+            let message = match err {
+                koopa::front::span::Error::Normal(message)
+                | koopa::front::span::Error::Fatal(message) => message,
+            };
+            Diagnostic::error(
+                format!("internal compiler error: invalid Koopa IR: {}", message),
+                None,
+            )
+        })
+}
+
+/// A run of global data: words with values, or zero bytes.
+enum DataChunk {
+    Words(Vec<i32>),
+    Zero(usize),
+}
+
+/// Appends the data of a global initializer, running zeros together.
+fn flatten_init(program: &koopa::ir::Program, init: koopa::ir::Value, out: &mut Vec<DataChunk>) {
+    let init = program.borrow_value(init);
+    let zero = match init.kind() {
+        koopa::ir::ValueKind::Integer(int) if int.value() != 0 => {
+            match out.last_mut() {
+                Some(DataChunk::Words(words)) => words.push(int.value()),
+                _ => out.push(DataChunk::Words(vec![int.value()])),
+            }
+            return;
+        }
+        // Undefined data may as well be zero.
+        koopa::ir::ValueKind::Integer(_)
+        | koopa::ir::ValueKind::ZeroInit(_)
+        | koopa::ir::ValueKind::Undef(_) => init.ty().size(),
+        koopa::ir::ValueKind::Aggregate(agg) => {
+            for &elem in agg.elems() {
+                flatten_init(program, elem, out);
+            }
+            return;
+        }
+        _ => unreachable!(),
+    };
+    match out.last_mut() {
+        Some(DataChunk::Zero(bytes)) => *bytes += zero,
+        _ => out.push(DataChunk::Zero(zero)),
+    }
+}
+
+/// The globals a module stores to, directly or through `getelemptr`,
+/// `getptr` and block parameters, or lets a pointer to escape from, so that
+/// they cannot go in read-only data.
+pub fn written_globals(ir: &str) -> Result<HashSet<String>, Diagnostic> {
+    let program = parse_koopa(ir)?;
+    let mut written = HashSet::new();
+    for &func in program.func_layout() {
+        let func_data = program.func(func);
+        // The global each local pointer points into.
+        let mut derived: HashMap<koopa::ir::Value, String> = HashMap::new();
+        let root =
+            |derived: &HashMap<koopa::ir::Value, String>, value: koopa::ir::Value| match value
+                .is_global()
+            {
+                true => Some(global_name(&program, value)),
+                false => derived.get(&value).cloned(),
+            };
+        for (_, node) in func_data.layout().bbs() {
+            for &inst in node.insts().keys() {
+                let escaping: Vec<koopa::ir::Value> = match func_data.dfg().value(inst).kind() {
+                    koopa::ir::ValueKind::GetElemPtr(gep) => {
+                        if let Some(global) = root(&derived, gep.src()) {
+                            derived.insert(inst, global);
+                        }
+                        continue;
+                    }
+                    koopa::ir::ValueKind::GetPtr(ptr) => {
+                        if let Some(global) = root(&derived, ptr.src()) {
+                            derived.insert(inst, global);
+                        }
+                        continue;
+                    }
+                    koopa::ir::ValueKind::Store(store) => vec![store.dest(), store.value()],
+                    koopa::ir::ValueKind::Call(call) => call.args().to_vec(),
+                    // A block parameter points wherever its argument does.
+                    koopa::ir::ValueKind::Jump(jump) => {
+                        let edges = [(jump.target(), jump.args())];
+                        bind_params(func_data, &edges, &mut derived, root);
+                        jump.args().to_vec()
+                    }
+                    koopa::ir::ValueKind::Branch(branch) => {
+                        let edges = [
+                            (branch.true_bb(), branch.true_args()),
+                            (branch.false_bb(), branch.false_args()),
+                        ];
+                        bind_params(func_data, &edges, &mut derived, root);
+                        [branch.true_args(), branch.false_args()].concat()
+                    }
+                    koopa::ir::ValueKind::Return(ret) => ret.value().into_iter().collect(),
+                    _ => continue,
+                };
+                written.extend(escaping.into_iter().filter_map(|v| root(&derived, v)));
+            }
+        }
+    }
+    Ok(written)
+}
+
+/// Records the global each parameter of the target blocks of `edges`
+/// points into, if its argument points into one.
+fn bind_params(
+    func_data: &koopa::ir::FunctionData,
+    edges: &[(koopa::ir::BasicBlock, &[koopa::ir::Value])],
+    derived: &mut HashMap<koopa::ir::Value, String>,
+    root: impl Fn(&HashMap<koopa::ir::Value, String>, koopa::ir::Value) -> Option<String>,
+) {
+    for &(target, args) in edges {
+        for (&param, &arg) in func_data.dfg().bb(target).params().iter().zip(args) {
+            if let Some(global) = root(derived, arg) {
+                derived.insert(param, global);
+            }
+        }
+    }
+}
+
+/// The most bytes an assembly line can take once the assembler expands
+/// pseudo-instructions.
+fn max_line_size(line: &str) -> i64 {
+    match line.split_whitespace().next() {
+        None => 0,
+        Some(op) if op.ends_with(':') || op.starts_with('.') => 0,
+        Some("li" | "la" | "call") => 8,
+        Some(_) => 4,
+    }
+}
+
+/// Rewrites every `bnez` whose target may lie beyond the ±4 KiB reach of a
+/// conditional branch into a `beqz` over a `j`, which reaches ±1 MiB.
+/// Lines are sized as large as they can get, so a branch left alone is
+/// always in range.
+fn relax_branches(code: &str, func: &str) -> String {
+    let mut lines: Vec<String> = code.lines().map(str::to_string).collect();
+    let mut relaxed = 0;
+    loop {
+        let mut labels = HashMap::new();
+        let mut addrs = Vec::with_capacity(lines.len());
+        let mut addr = 0;
+        for line in lines.iter() {
+            if let Some(label) = line.strip_suffix(':') {
+                labels.insert(label.to_string(), addr);
+            }
+            addrs.push(addr);
+            addr += max_line_size(line);
+        }
+        let far: Vec<usize> = (0..lines.len())
+            .filter(|&i| match lines[i].strip_prefix("bnez ") {
+                Some(branch) => {
+                    let (_, target) = branch.split_once(", ").unwrap();
+                    !(-4096..4096).contains(&(labels[target] - addrs[i]))
+                }
+                None => false,
+            })
+            .collect();
+        if far.is_empty() {
+            break;
+        }
+        // Rewriting makes code longer, which can push other branches out of
+        // range; go round again until none are.
+        for i in far.into_iter().rev() {
+            let (reg, target) = lines[i]["bnez ".len()..].split_once(", ").unwrap();
+            let skip = format!(".L{}.near.{}", func, relaxed);
+            let replacement = [
+                format!("beqz {}, {}", reg, skip),
+                format!("j {}", target),
+                format!("{}:", skip),
+            ];
+            lines.splice(i..=i, replacement);
+            relaxed += 1;
+        }
+    }
+    let mut out = lines.join("\n");
+    out += "\n";
     out
 }
 fn stmt2str(
@@ -79,25 +340,24 @@ fn stmt2str(
     func_data: &koopa::ir::FunctionData,
     value: &koopa::ir::Value,
     reg_count: usize,
-    stack_map: &mut HashMap<koopa::ir::Value, String>,
+    stack_map: &mut HashMap<koopa::ir::Value, i32>,
     stack_offset: &mut i32,
     size: i32,
 ) -> String {
-    println!(
-        "Processing statement: {:?}",
-        func_data.dfg().value(*value).kind()
-    );
+    // A global used as an operand stands for its address; it is not in the
+    // function's own values.
+    if value.is_global() {
+        return format!("la t{}, {}\n", reg_count, global_name(program, *value));
+    }
     let mut out = String::new();
     match func_data.dfg().value(*value).kind() {
         koopa::ir::ValueKind::Integer(int) => out = format!("li t{}, {}\n", reg_count, int.value()),
         koopa::ir::ValueKind::Return(ret) => {
             if ret.value().is_none() {
-                out += &format!("lw ra {}(sp)\n", size - 4);
-                out += &format!("addi sp, sp, {}\n", size);
-                out += "ret\n";
+                out += &epilogue(size);
             } else {
                 if stack_map.contains_key(&ret.value().unwrap()) {
-                    out += &format!("lw a0, {}\n", stack_map.get(&ret.value().unwrap()).unwrap());
+                    out += &stack_access("lw", "a0", stack_map[&ret.value().unwrap()]);
                 } else {
                     out += &stmt2str(
                         program,
@@ -110,9 +370,7 @@ fn stmt2str(
                     );
                     out += &format!("mv a0, t{}\n", reg_count);
                 }
-                out += &format!("lw ra {}(sp)\n", size - 4);
-                out += &format!("addi sp, sp, {}\n", size);
-                out += "ret\n";
+                out += &epilogue(size);
             }
         }
         koopa::ir::ValueKind::Binary(bin) => {
@@ -120,7 +378,7 @@ fn stmt2str(
             let lhs_reg = format!("t{}", reg_count);
             let rhs_reg = format!("t{}", reg_count + 1);
             if stack_map.contains_key(&bin.lhs()) {
-                out += &format!("lw {}, {}\n", lhs_reg, stack_map.get(&bin.lhs()).unwrap());
+                out += &stack_access("lw", &lhs_reg, stack_map[&bin.lhs()]);
             } else {
                 out += &stmt2str(
                     program,
@@ -133,7 +391,7 @@ fn stmt2str(
                 );
             }
             if stack_map.contains_key(&bin.rhs()) {
-                out += &format!("lw {}, {}\n", rhs_reg, stack_map.get(&bin.rhs()).unwrap());
+                out += &stack_access("lw", &rhs_reg, stack_map[&bin.rhs()]);
             } else {
                 out += &stmt2str(
                     program,
@@ -186,20 +444,60 @@ fn stmt2str(
                     out += &format!("slt {}, {}, {}\n", dest_reg, rhs_reg, lhs_reg);
                     out += &format!("xori {}, {}, 1\n", dest_reg, dest_reg);
                 }
-                _ => unreachable!(),
+                koopa::ir::BinaryOp::Gt => {
+                    out += &format!("sgt {}, {}, {}\n", dest_reg, lhs_reg, rhs_reg);
+                }
+                koopa::ir::BinaryOp::Ge => {
+                    out += &format!("slt {}, {}, {}\n", dest_reg, lhs_reg, rhs_reg);
+                    out += &format!("xori {}, {}, 1\n", dest_reg, dest_reg);
+                }
+                koopa::ir::BinaryOp::Xor => {
+                    out += &format!("xor {}, {}, {}\n", dest_reg, lhs_reg, rhs_reg);
+                }
+                koopa::ir::BinaryOp::Shl => {
+                    out += &format!("sll {}, {}, {}\n", dest_reg, lhs_reg, rhs_reg);
+                }
+                koopa::ir::BinaryOp::Shr => {
+                    out += &format!("srl {}, {}, {}\n", dest_reg, lhs_reg, rhs_reg);
+                }
+                koopa::ir::BinaryOp::Sar => {
+                    out += &format!("sra {}, {}, {}\n", dest_reg, lhs_reg, rhs_reg);
+                }
             }
-            out += &format!("sw {}, {}(sp)\n", dest_reg, *stack_offset + 4);
             *stack_offset += 4;
-            stack_map.insert(*value, format!("{}(sp)", stack_offset));
+            out += &stack_access("sw", &dest_reg, *stack_offset);
+            stack_map.insert(*value, *stack_offset);
         }
-        koopa::ir::ValueKind::Alloc(_) => {}
-        koopa::ir::ValueKind::Store(store) => {
+        koopa::ir::ValueKind::Alloc(_) => {
+            // The storage itself lives in the frame; its address is the value.
+            let bytes = pointee_size(func_data.dfg().value(*value).ty());
+            stack_map.insert(*value, *stack_offset + 4);
+            *stack_offset += bytes as i32;
+        }
+        koopa::ir::ValueKind::Store(store) if store.dest().is_global() => {
             if stack_map.contains_key(&store.value()) {
-                out += &format!(
-                    "lw t{}, {}\n",
+                out += &stack_access("lw", &format!("t{}", reg_count), stack_map[&store.value()]);
+            } else {
+                out += &stmt2str(
+                    program,
+                    func_data,
+                    &store.value(),
                     reg_count,
-                    stack_map.get(&store.value()).unwrap()
+                    stack_map,
+                    stack_offset,
+                    size,
                 );
+            }
+            out += &format!(
+                "la t{}, {}\n",
+                reg_count + 1,
+                global_name(program, store.dest())
+            );
+            out += &format!("sw t{}, 0(t{})\n", reg_count, reg_count + 1);
+        }
+        koopa::ir::ValueKind::Store(store) => {
+            if stack_map.contains_key(&store.value()) {
+                out += &stack_access("lw", &format!("t{}", reg_count), stack_map[&store.value()]);
             } else {
                 out += &stmt2str(
                     program,
@@ -211,67 +509,127 @@ fn stmt2str(
                     size,
                 );
             }
-            if !stack_map.contains_key(&store.dest()) {
-                *stack_offset += 4;
-                stack_map.insert(store.dest(), format!("{}(sp)", stack_offset));
+            let dest = stack_map[&store.dest()];
+            if is_alloc(func_data, store.dest()) {
+                out += &stack_access("sw", &format!("t{}", reg_count), dest);
+            } else {
+                // Through a pointer computed earlier.
+                out += &stack_access("lw", &format!("t{}", reg_count + 1), dest);
+                out += &format!("sw t{}, 0(t{})\n", reg_count, reg_count + 1);
             }
-            out += &format!(
-                "sw t{}, {}\n",
-                reg_count,
-                stack_map.get(&store.dest()).unwrap()
-            );
         }
         koopa::ir::ValueKind::Load(load) => {
-            out += &format!(
-                "lw t{}, {}\n",
-                reg_count,
-                stack_map.get(&load.src()).unwrap()
-            );
+            if load.src().is_global() {
+                out += &format!("la t{}, {}\n", reg_count, global_name(program, load.src()));
+                out += &format!("lw t{}, 0(t{})\n", reg_count, reg_count);
+            } else {
+                out += &stack_access("lw", &format!("t{}", reg_count), stack_map[&load.src()]);
+                if !is_alloc(func_data, load.src()) {
+                    // Through a pointer computed earlier.
+                    out += &format!("lw t{}, 0(t{})\n", reg_count, reg_count);
+                }
+            }
             *stack_offset += 4;
-            stack_map.insert(*value, format!("{}(sp)", stack_offset));
-            out += &format!("sw t{}, {}(sp)\n", reg_count, stack_offset);
+            stack_map.insert(*value, *stack_offset);
+            out += &stack_access("sw", &format!("t{}", reg_count), *stack_offset);
         }
-        koopa::ir::ValueKind::Branch(branch) => {
-            out += &stmt2str(
+        koopa::ir::ValueKind::GetElemPtr(gep) => {
+            out += &element_address(
                 program,
                 func_data,
-                &branch.cond(),
+                value,
+                (gep.src(), gep.index()),
                 reg_count,
                 stack_map,
                 stack_offset,
                 size,
             );
-            out += &format!(
-                "bnez t{}, {}\n",
+        }
+        koopa::ir::ValueKind::GetPtr(ptr) => {
+            out += &element_address(
+                program,
+                func_data,
+                value,
+                (ptr.src(), ptr.index()),
                 reg_count,
-                &func_data
-                    .dfg()
-                    .bb(branch.true_bb())
-                    .name()
-                    .as_ref()
-                    .unwrap()[1..]
-            );
-            out += &format!(
-                "j {}\n",
-                &func_data
-                    .dfg()
-                    .bb(branch.false_bb())
-                    .name()
-                    .as_ref()
-                    .unwrap()[1..]
+                stack_map,
+                stack_offset,
+                size,
             );
         }
+        koopa::ir::ValueKind::Branch(branch) => {
+            if stack_map.contains_key(&branch.cond()) {
+                out += &stack_access("lw", &format!("t{}", reg_count), stack_map[&branch.cond()]);
+            } else {
+                out += &stmt2str(
+                    program,
+                    func_data,
+                    &branch.cond(),
+                    reg_count,
+                    stack_map,
+                    stack_offset,
+                    size,
+                );
+            }
+            let true_name = block_label(func_data, branch.true_bb());
+            let false_name = block_label(func_data, branch.false_bb());
+            if branch.true_args().is_empty() {
+                out += &format!("bnez t{}, {}\n", reg_count, true_name);
+                out += &parallel_copy(
+                    program,
+                    func_data,
+                    branch.false_bb(),
+                    branch.false_args(),
+                    stack_map,
+                    stack_offset,
+                    size,
+                );
+                out += &format!("j {}\n", false_name);
+            } else {
+                // The copies for the true edge must not run on the false
+                // one, so that edge gets a block of its own.
+                let from = func_data.layout().parent_bb(*value).unwrap();
+                let edge = format!("{}.true", block_label(func_data, from));
+                out += &format!("bnez t{}, {}\n", reg_count, edge);
+                out += &parallel_copy(
+                    program,
+                    func_data,
+                    branch.false_bb(),
+                    branch.false_args(),
+                    stack_map,
+                    stack_offset,
+                    size,
+                );
+                out += &format!("j {}\n", false_name);
+                out += &format!("{}:\n", edge);
+                out += &parallel_copy(
+                    program,
+                    func_data,
+                    branch.true_bb(),
+                    branch.true_args(),
+                    stack_map,
+                    stack_offset,
+                    size,
+                );
+                out += &format!("j {}\n", true_name);
+            }
+        }
         koopa::ir::ValueKind::Jump(jump) => {
-            out += &format!(
-                "j {}\n",
-                &func_data.dfg().bb(jump.target()).name().as_ref().unwrap()[1..]
+            out += &parallel_copy(
+                program,
+                func_data,
+                jump.target(),
+                jump.args(),
+                stack_map,
+                stack_offset,
+                size,
             );
+            out += &format!("j {}\n", block_label(func_data, jump.target()));
         }
         koopa::ir::ValueKind::Call(call) => {
-            let mut arg_pos = 0;
-            for arg in call.args() {
+            for (arg_pos, arg) in call.args().iter().enumerate() {
                 if stack_map.contains_key(arg) {
-                    out += &format!("lw t{}, {}\n", reg_count, stack_map.get(arg).unwrap());
+                    out += &stack_access("lw", &format!("t{}", reg_count), stack_map[arg]);
                 } else {
                     out += &stmt2str(
                         program,
@@ -283,16 +641,637 @@ fn stmt2str(
                         size,
                     );
                 }
-                out += &format!("sw t{}, {}(sp)\n", reg_count, arg_pos * 4);
-                arg_pos += 1;
+                if arg_pos < ARG_REGS {
+                    out += &format!("mv a{}, t{}\n", arg_pos, reg_count);
+                } else {
+                    let offset = (arg_pos - ARG_REGS) as i32 * 4;
+                    out += &stack_access("sw", &format!("t{}", reg_count), offset);
+                }
             }
             out += &format!("call {}\n", &program.func(call.callee()).name()[1..]);
             *stack_offset += 4;
-            stack_map.insert(*value, format!("{}(sp)", *stack_offset));
-            out += &format!("sw a0, {}\n", stack_map.get(value).unwrap());
-            out += &format!("lw t{}, {}\n", reg_count, stack_map.get(value).unwrap());
+            stack_map.insert(*value, *stack_offset);
+            out += &stack_access("sw", "a0", *stack_offset);
+            out += &stack_access("lw", &format!("t{}", reg_count), *stack_offset);
         }
         _ => {}
     }
     out
 }
+
+/// Where a block argument is copied from.
+#[derive(PartialEq)]
+enum CopySource {
+    Slot(i32),
+    Value(koopa::ir::Value),
+}
+
+/// Copies `args` to the parameters of `target` as if all at once, so no
+/// copy overwrites a parameter that another still has to read, as in
+/// `jump %loop(%b, %a)`. A cycle of such copies is broken by saving one
+/// parameter in the scratch word below `ra`.
+fn parallel_copy(
+    program: &koopa::ir::Program,
+    func_data: &koopa::ir::FunctionData,
+    target: koopa::ir::BasicBlock,
+    args: &[koopa::ir::Value],
+    stack_map: &mut HashMap<koopa::ir::Value, i32>,
+    stack_offset: &mut i32,
+    size: i32,
+) -> String {
+    let mut out = String::new();
+    let scratch = size - 8;
+    let mut pending = Vec::new();
+    for (param, arg) in func_data.dfg().bb(target).params().iter().zip(args) {
+        let dest = stack_map[param];
+        let src = match stack_map.get(arg) {
+            Some(&slot) => CopySource::Slot(slot),
+            None => CopySource::Value(*arg),
+        };
+        if src != CopySource::Slot(dest) {
+            pending.push((dest, src));
+        }
+    }
+    while !pending.is_empty() {
+        let ready = pending.iter().position(|(dest, _)| {
+            pending
+                .iter()
+                .all(|(_, src)| *src != CopySource::Slot(*dest))
+        });
+        match ready {
+            Some(i) => {
+                let (dest, src) = pending.remove(i);
+                match src {
+                    CopySource::Slot(slot) => out += &stack_access("lw", "t0", slot),
+                    CopySource::Value(value) => {
+                        out +=
+                            &stmt2str(program, func_data, &value, 0, stack_map, stack_offset, size)
+                    }
+                }
+                out += &stack_access("sw", "t0", dest);
+            }
+            None => {
+                let dest = pending[0].0;
+                out += &stack_access("lw", "t0", dest);
+                out += &stack_access("sw", "t0", scratch);
+                for (_, src) in pending.iter_mut() {
+                    if *src == CopySource::Slot(dest) {
+                        *src = CopySource::Slot(scratch);
+                    }
+                }
+            }
+        }
+    }
+    out
+}
+
+/// Computes `src + index * size` for `getelemptr` and `getptr`, where `size`
+/// is that of the element the result points to.
+#[allow(clippy::too_many_arguments)]
+fn element_address(
+    program: &koopa::ir::Program,
+    func_data: &koopa::ir::FunctionData,
+    value: &koopa::ir::Value,
+    (src, index): (koopa::ir::Value, koopa::ir::Value),
+    reg_count: usize,
+    stack_map: &mut HashMap<koopa::ir::Value, i32>,
+    stack_offset: &mut i32,
+    size: i32,
+) -> String {
+    let mut out = String::new();
+    let base = format!("t{}", reg_count);
+    if src.is_global() {
+        out += &format!("la {}, {}\n", base, global_name(program, src));
+    } else if is_alloc(func_data, src) {
+        out += &stack_address(&base, stack_map[&src]);
+    } else {
+        out += &stack_access("lw", &base, stack_map[&src]);
+    }
+    if stack_map.contains_key(&index) {
+        out += &stack_access("lw", &format!("t{}", reg_count + 1), stack_map[&index]);
+    } else {
+        out += &stmt2str(
+            program,
+            func_data,
+            &index,
+            reg_count + 1,
+            stack_map,
+            stack_offset,
+            size,
+        );
+    }
+    let elem = pointee_size(func_data.dfg().value(*value).ty());
+    out += &format!("li t{}, {}\n", reg_count + 2, elem);
+    out += &format!(
+        "mul t{}, t{}, t{}\n",
+        reg_count + 1,
+        reg_count + 1,
+        reg_count + 2
+    );
+    out += &format!("add {}, {}, t{}\n", base, base, reg_count + 1);
+    *stack_offset += 4;
+    stack_map.insert(*value, *stack_offset);
+    out += &stack_access("sw", &base, *stack_offset);
+    out
+}
+
+/// Whether `value` is a local `alloc`, whose stack slot is the storage
+/// itself rather than a pointer to it.
+fn is_alloc(func_data: &koopa::ir::FunctionData, value: koopa::ir::Value) -> bool {
+    !value.is_global()
+        && matches!(
+            func_data.dfg().value(value).kind(),
+            koopa::ir::ValueKind::Alloc(_)
+        )
+}
+
+/// The size of what a pointer type points to.
+fn pointee_size(ty: &koopa::ir::Type) -> usize {
+    match ty.kind() {
+        koopa::ir::TypeKind::Pointer(base) => base.size(),
+        _ => unreachable!(),
+    }
+}
+
+/// Puts the address `sp + offset` in `reg`.
+fn stack_address(reg: &str, offset: i32) -> String {
+    if (-2048..2048).contains(&offset) {
+        format!("addi {}, sp, {}\n", reg, offset)
+    } else {
+        format!("li {}, {}\nadd {}, {}, sp\n", reg, offset, reg, reg)
+    }
+}
+
+/// Loads or stores `reg` at `offset(sp)`. Offsets beyond the 12-bit
+/// immediate of `lw` and `sw` are added to `sp` in `t6` first.
+fn stack_access(op: &str, reg: &str, offset: i32) -> String {
+    if (-2048..2048).contains(&offset) {
+        format!("{} {}, {}(sp)\n", op, reg, offset)
+    } else {
+        format!("li t6, {}\nadd t6, t6, sp\n{} {}, 0(t6)\n", offset, op, reg)
+    }
+}
+
+/// Moves `sp` by `delta`, through `t6` when it does not fit `addi`.
+fn adjust_sp(delta: i32) -> String {
+    if (-2048..2048).contains(&delta) {
+        format!("addi sp, sp, {}\n", delta)
+    } else {
+        format!("li t6, {}\nadd sp, sp, t6\n", delta)
+    }
+}
+
+/// Restores `ra` and `sp` and returns.
+fn epilogue(size: i32) -> String {
+    stack_access("lw", "ra", size - 4) + &adjust_sp(size) + "ret\n"
+}
+
+/// The assembly label of a basic block. Block names are only unique within
+/// a function, so the label starts with the function's. Koopa names have no
+/// dots, so labels joined with them cannot clash with each other, nor with
+/// the ones the back end makes up for branches.
+fn block_label(func_data: &koopa::ir::FunctionData, bb: koopa::ir::BasicBlock) -> String {
+    format!(
+        ".L{}.{}",
+        &func_data.name()[1..],
+        &func_data.dfg().bb(bb).name().as_ref().unwrap()[1..]
+    )
+}
+
+/// The assembly symbol of a global `alloc`.
+fn global_name(program: &koopa::ir::Program, global: koopa::ir::Value) -> String {
+    program.borrow_value(global).name().as_ref().unwrap()[1..].to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+
+    fn lower(ir: &str) -> String {
+        let asm = ir2riscv(
+            ir.to_string(),
+            &HashSet::new(),
+            &written_globals(ir).unwrap(),
+            &HashMap::new(),
+        )
+        .unwrap();
+        assemble(&asm);
+        asm
+    }
+
+    /// Assembles `asm` with `llvm-mc` when it is installed, so that output
+    /// no assembler accepts fails the test.
+    fn assemble(asm: &str) {
+        let child = Command::new("llvm-mc")
+            .args([
+                "-triple=riscv32",
+                "-mattr=+m",
+                "-filetype=obj",
+                "-o",
+                "/dev/null",
+            ])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn();
+        let Ok(mut child) = child else { return };
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(asm.as_bytes())
+            .unwrap();
+        let output = child.wait_with_output().unwrap();
+        assert!(
+            output.status.success(),
+            "{}\n{}",
+            String::from_utf8_lossy(&output.stderr),
+            asm
+        );
+    }
+
+    /// Whether `asm` has `expected` as consecutive lines.
+    fn has_lines(asm: &str, expected: &[&str]) -> bool {
+        let lines: Vec<&str> = asm.lines().collect();
+        lines.windows(expected.len()).any(|w| w == expected)
+    }
+
+    #[test]
+    fn arguments_past_the_eighth_go_on_the_stack() {
+        let asm = lower(
+            "fun @f(@a: i32, @b: i32, @c: i32, @d: i32, @e: i32, @f: i32, @g: i32, @h: i32, @i: i32, @j: i32): i32 {
+%entry:
+  ret @j
+}
+
+fun @main(): i32 {
+%entry:
+  %r = call @f(1, 2, 3, 4, 5, 6, 7, 8, 9, 10)
+  ret %r
+}
+",
+        );
+        assert!(has_lines(
+            &asm,
+            &["li t0, 8", "mv a7, t0", "li t0, 9", "sw t0, 0(sp)"]
+        ));
+        assert!(has_lines(&asm, &["li t0, 10", "sw t0, 4(sp)", "call f"]));
+    }
+
+    #[test]
+    fn large_frames_reach_their_slots_through_t6() {
+        let mut ir = String::from("fun @main(): i32 {\n%entry:\n");
+        for i in 0..300 {
+            ir += &format!("  %n{} = add {}, 1\n", i, i);
+        }
+        ir += "  ret %n299\n}\n";
+        let asm = lower(&ir);
+        // A word for each value, constants included, and one for `ra`.
+        assert!(has_lines(
+            &asm,
+            &[
+                "li t6, -3616",
+                "add sp, sp, t6",
+                "li t6, 3612",
+                "add t6, t6, sp",
+                "sw ra, 0(t6)"
+            ]
+        ));
+        assert!(has_lines(
+            &asm,
+            &["lw ra, 0(t6)", "li t6, 3616", "add sp, sp, t6", "ret"]
+        ));
+    }
+
+    #[test]
+    fn far_branches_are_relaxed() {
+        let mut ir = String::from(
+            "fun @main(): i32 {
+%entry:
+  jump %top
+%top:
+",
+        );
+        for i in 0..200 {
+            ir += &format!("  %n{} = add 1, 2\n", i);
+        }
+        ir += "  br %n0, %top, %out
+%out:
+  ret 0
+}
+";
+        let asm = lower(&ir);
+        assert!(!asm.contains("bnez t0, .Lmain.top"));
+        assert!(has_lines(
+            &asm,
+            &[
+                "beqz t0, .Lmain.near.0",
+                "j .Lmain.top",
+                ".Lmain.near.0:",
+                "j .Lmain.out"
+            ]
+        ));
+    }
+
+    #[test]
+    fn every_binary_operator_is_lowered() {
+        let ops = [
+            ("ne", "snez"),
+            ("eq", "seqz"),
+            ("gt", "sgt"),
+            ("lt", "slt"),
+            ("ge", "xori"),
+            ("le", "xori"),
+            ("add", "add"),
+            ("sub", "sub"),
+            ("mul", "mul"),
+            ("div", "div"),
+            ("mod", "rem"),
+            ("and", "and"),
+            ("or", "or"),
+            ("xor", "xor"),
+            ("shl", "sll"),
+            ("shr", "srl"),
+            ("sar", "sra"),
+        ];
+        for (op, inst) in ops {
+            let asm = lower(&format!(
+                "fun @main(): i32 {{\n%entry:\n  %r = {} 7, 3\n  ret %r\n}}\n",
+                op
+            ));
+            assert!(
+                asm.lines()
+                    .any(|line| line.starts_with(&format!("{} ", inst))),
+                "{}:\n{}",
+                op,
+                asm
+            );
+        }
+    }
+
+    #[test]
+    fn element_pointers_are_scaled_by_element_size() {
+        let asm = lower(
+            "fun @main(): i32 {
+%entry:
+  %a = alloc [[i32, 3], 2]
+  %row = getelemptr %a, 1
+  %p = getelemptr %row, 2
+  %q = getptr %p, 1
+  store 9, %q
+  ret 0
+}
+",
+        );
+        assert!(has_lines(
+            &asm,
+            &["addi t0, sp, 0", "li t1, 1", "li t2, 12", "mul t1, t1, t2"]
+        ));
+        assert!(has_lines(&asm, &["li t1, 2", "li t2, 4", "mul t1, t1, t2"]));
+        assert!(has_lines(&asm, &["li t1, 1", "li t2, 4", "mul t1, t1, t2"]));
+    }
+
+    #[test]
+    fn frames_hold_whole_allocations() {
+        let asm = lower(
+            "fun @main(): i32 {
+%entry:
+  %big = alloc [i32, 600]
+  %x = alloc i32
+  store 1, %x
+  ret 0
+}
+",
+        );
+        assert!(has_lines(&asm, &["li t6, -2432", "add sp, sp, t6"]));
+        assert!(has_lines(
+            &asm,
+            &["li t0, 1", "li t6, 2400", "add t6, t6, sp", "sw t0, 0(t6)"]
+        ));
+    }
+
+    #[test]
+    fn global_data_is_flattened_and_split_by_writes() {
+        let asm = lower(
+            "global @table = alloc [i32, 12], {1, 2, 3, 4, 5, 6, 7, 8, 9, 0, 0, 0}
+global @zeros = alloc [i32, 4], zeroinit
+global @u = alloc i32, undef
+global @g = alloc [i32, 3], {0, 0, 7}
+
+fun @main(): i32 {
+%entry:
+  %p = getelemptr @g, 0
+  store 1, %p
+  %t = getelemptr @table, 0
+  %v = load %t
+  ret %v
+}
+",
+        );
+        let (data, rodata) = asm.split_once(".section .rodata").unwrap();
+        assert!(has_lines(data, &[".globl g", "g:", ".zero 8", ".word 7"]));
+        assert!(has_lines(
+            rodata,
+            &[
+                ".globl table",
+                "table:",
+                ".word 1, 2, 3, 4, 5, 6, 7, 8",
+                ".word 9",
+                ".zero 12",
+            ]
+        ));
+        assert!(has_lines(rodata, &["zeros:", ".zero 16"]));
+        assert!(has_lines(rodata, &["u:", ".zero 4"]));
+    }
+
+    #[test]
+    fn block_arguments_are_copied_on_each_edge() {
+        let asm = lower(
+            "fun @main(): i32 {
+%entry:
+  jump %loop(0, 1)
+%loop(%a: i32, %i: i32):
+  %c = lt %i, 10
+  br %c, %body(%a, %i), %done(%a)
+%body(%x: i32, %k: i32):
+  %s = add %x, %k
+  %j = add %k, 1
+  jump %loop(%s, %j)
+%done(%r: i32):
+  ret %r
+}
+",
+        );
+        // Parameter slots follow the order of the blocks.
+        assert!(has_lines(
+            &asm,
+            &[
+                "li t0, 0",
+                "sw t0, 0(sp)",
+                "li t0, 1",
+                "sw t0, 4(sp)",
+                "j .Lmain.loop"
+            ]
+        ));
+        // The false edge falls through; the true edge has a block of its own.
+        assert!(has_lines(
+            &asm,
+            &[
+                "bnez t0, .Lmain.loop.true",
+                "lw t0, 0(sp)",
+                "sw t0, 16(sp)",
+                "j .Lmain.done",
+                ".Lmain.loop.true:",
+                "lw t0, 0(sp)",
+                "sw t0, 8(sp)",
+                "lw t0, 4(sp)",
+                "sw t0, 12(sp)",
+                "j .Lmain.body",
+            ]
+        ));
+        assert!(has_lines(&asm, &[".Lmain.done:", "lw a0, 16(sp)"]));
+    }
+
+    #[test]
+    fn block_labels_are_qualified_by_their_function() {
+        let block = "%entry:
+  jump %loop
+%loop:
+  br 0, %loop, %end
+%end:
+  ret 0
+}
+";
+        let asm = lower(&format!(
+            "fun @f(): i32 {{\n{}\nfun @main(): i32 {{\n{}",
+            block, block
+        ));
+        for func in ["f", "main"] {
+            assert!(has_lines(
+                &asm,
+                &[
+                    &format!(".L{}.loop:", func),
+                    "li t0, 0",
+                    &format!("bnez t0, .L{}.loop", func),
+                    &format!("j .L{}.end", func),
+                    &format!(".L{}.end:", func),
+                ]
+            ));
+        }
+    }
+
+    #[test]
+    fn swapped_block_arguments_go_through_the_scratch_word() {
+        let asm = lower(
+            "fun @main(): i32 {
+%entry:
+  jump %l(1, 2)
+%l(%u: i32, %v: i32):
+  %z = eq %u, 2
+  br %z, %end, %l(%v, %u)
+%end:
+  ret %v
+}
+",
+        );
+        // The frame has a word for each value, constants included.
+        assert!(has_lines(&asm, &["addi sp, sp, -48", "sw ra, 44(sp)"]));
+        assert!(has_lines(
+            &asm,
+            &[
+                "bnez t0, .Lmain.end",
+                "lw t0, 0(sp)",
+                "sw t0, 40(sp)",
+                "lw t0, 4(sp)",
+                "sw t0, 0(sp)",
+                "lw t0, 40(sp)",
+                "sw t0, 4(sp)",
+                "j .Lmain.l",
+            ]
+        ));
+    }
+
+    #[test]
+    fn globals_can_be_operands() {
+        let asm = lower(
+            "global @g = alloc i32, 3
+
+fun @f(@p: *i32): i32 {
+%entry:
+  %v = load @p
+  ret %v
+}
+
+fun @main(): i32 {
+%entry:
+  %r = call @f(@g)
+  ret %r
+}
+",
+        );
+        assert!(has_lines(&asm, &["la t0, g", "mv a0, t0", "call f"]));
+    }
+
+    #[test]
+    fn globals_passed_as_block_arguments_are_written() {
+        let ir = "global @g = alloc i32, 1
+
+fun @main(): i32 {
+%entry:
+  jump %b(@g)
+%b(%p: *i32):
+  store 2, %p
+  ret 0
+}
+";
+        assert!(written_globals(ir).unwrap().contains("g"));
+        let asm = lower(ir);
+        assert!(has_lines(&asm, &["la t0, g", "sw t0, 0(sp)", "j .Lmain.b"]));
+    }
+
+    #[test]
+    fn globals_are_exported_under_their_symbols() {
+        let ir = "global @g_0 = alloc i32, 1
+global @h_0 = alloc i32, zeroinit
+
+fun @main(): i32 {
+%entry:
+  store 2, @g_0
+  %x = load @h_0
+  ret %x
+}
+";
+        let symbols = HashMap::from([
+            ("g_0".to_string(), "g".to_string()),
+            ("h_0".to_string(), "h".to_string()),
+        ]);
+        let externs = HashSet::from(["h_0".to_string()]);
+        let asm = ir2riscv(
+            ir.to_string(),
+            &externs,
+            &written_globals(ir).unwrap(),
+            &symbols,
+        )
+        .unwrap();
+        assemble(&asm);
+        assert!(has_lines(&asm, &[".globl g", "g:", ".word 1"]));
+        assert!(has_lines(&asm, &["la t1, g", "sw t0, 0(t1)"]));
+        assert!(has_lines(&asm, &["la t0, h", "lw t0, 0(t0)"]));
+        assert!(!asm.contains("g_0") && !asm.contains("h_0") && !asm.contains("h:"));
+    }
+
+    #[test]
+    fn invalid_koopa_is_an_internal_error() {
+        let Err(err) = parse_koopa("fun @main(): i32 {\n%entry:\n  ret %x\n}\n") else {
+            panic!("undefined value parsed")
+        };
+        assert!(
+            err.message
+                .starts_with("internal compiler error: invalid Koopa IR: "),
+            "{}",
+            err.message
+        );
+    }
+}
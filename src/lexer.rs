@@ -0,0 +1,354 @@
+use crate::diag::{Diagnostic, Span};
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Tok {
+    Int,
+    Void,
+    Const,
+    Extern,
+    If,
+    Else,
+    While,
+    Break,
+    Continue,
+    Return,
+    Ident(String),
+    IntConst(i32),
+    LParen,
+    RParen,
+    LBrace,
+    RBrace,
+    Semi,
+    Comma,
+    Assign,
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    Percent,
+    Not,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+    EqEq,
+    Ne,
+    AndAnd,
+    OrOr,
+}
+
+impl fmt::Display for Tok {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Tok::Int => "int",
+            Tok::Void => "void",
+            Tok::Const => "const",
+            Tok::Extern => "extern",
+            Tok::If => "if",
+            Tok::Else => "else",
+            Tok::While => "while",
+            Tok::Break => "break",
+            Tok::Continue => "continue",
+            Tok::Return => "return",
+            Tok::Ident(id) => return write!(f, "{}", id),
+            Tok::IntConst(n) => return write!(f, "{}", n),
+            Tok::LParen => "(",
+            Tok::RParen => ")",
+            Tok::LBrace => "{",
+            Tok::RBrace => "}",
+            Tok::Semi => ";",
+            Tok::Comma => ",",
+            Tok::Assign => "=",
+            Tok::Plus => "+",
+            Tok::Minus => "-",
+            Tok::Star => "*",
+            Tok::Slash => "/",
+            Tok::Percent => "%",
+            Tok::Not => "!",
+            Tok::Lt => "<",
+            Tok::Gt => ">",
+            Tok::Le => "<=",
+            Tok::Ge => ">=",
+            Tok::EqEq => "==",
+            Tok::Ne => "!=",
+            Tok::AndAnd => "&&",
+            Tok::OrOr => "||",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+pub type Spanned = Result<(usize, Tok, usize), Diagnostic>;
+
+/// Turns preprocessed SysY source into a stream of `(lo, token, hi)` triples
+/// for the LALRPOP parser. Positions are offset by `base`, the position the
+/// text was registered at in the `SourceMap`. Errors are reported as
+/// diagnostics; the stream can be resumed after an error, which `-tokens`
+/// uses to dump the whole file.
+pub struct Lexer<'a> {
+    src: &'a str,
+    base: usize,
+    pos: usize,
+    prev: Option<Tok>,
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(src: &'a str, base: usize) -> Self {
+        Lexer {
+            src,
+            base,
+            pos: 0,
+            prev: None,
+        }
+    }
+
+    fn span(&self, lo: usize, hi: usize) -> Option<Span> {
+        Some(Span::new(self.base + lo, self.base + hi))
+    }
+
+    fn peek(&self, n: usize) -> u8 {
+        *self.src.as_bytes().get(self.pos + n).unwrap_or(&0)
+    }
+
+    /// Skips whitespace and line markers. Comments are already gone: the
+    /// preprocessor replaces them with spaces.
+    fn skip_trivia(&mut self) {
+        loop {
+            match (self.peek(0), self.peek(1)) {
+                (b' ' | b'\t' | b'\n' | b'\r' | b'\x0b' | b'\x0c', _) => self.pos += 1,
+                // Line markers left by the preprocessor.
+                (b'#', _) if self.pos == 0 || self.src.as_bytes()[self.pos - 1] == b'\n' => {
+                    while self.pos < self.src.len() && self.peek(0) != b'\n' {
+                        self.pos += 1;
+                    }
+                }
+                _ => return,
+            }
+        }
+    }
+
+    fn ident(&mut self) -> Tok {
+        let start = self.pos;
+        while self.peek(0).is_ascii_alphanumeric() || self.peek(0) == b'_' {
+            self.pos += 1;
+        }
+        match &self.src[start..self.pos] {
+            "int" => Tok::Int,
+            "void" => Tok::Void,
+            "const" => Tok::Const,
+            "extern" => Tok::Extern,
+            "if" => Tok::If,
+            "else" => Tok::Else,
+            "while" => Tok::While,
+            "break" => Tok::Break,
+            "continue" => Tok::Continue,
+            "return" => Tok::Return,
+            id => Tok::Ident(id.to_string()),
+        }
+    }
+
+    fn number(&mut self) -> Result<Tok, Diagnostic> {
+        let start = self.pos;
+        while self.peek(0).is_ascii_alphanumeric() || self.peek(0) == b'_' {
+            self.pos += 1;
+        }
+        let text = &self.src[start..self.pos];
+        let span = self.span(start, self.pos);
+        let (digits, radix, kind) = if text.starts_with("0x") || text.starts_with("0X") {
+            (&text[2..], 16, "hexadecimal")
+        } else if text.len() > 1 && text.starts_with('0') {
+            (&text[1..], 8, "octal")
+        } else {
+            (text, 10, "decimal")
+        };
+        if digits.is_empty() {
+            return Err(Diagnostic::error(
+                format!("missing digits after the {} prefix `{}`", kind, text),
+                span,
+            )
+            .with_code("E0012"));
+        }
+        let mut value: u64 = 0;
+        for (i, c) in digits.char_indices() {
+            let digit = match c.to_digit(radix) {
+                Some(d) => d,
+                None if c.is_ascii_digit() => {
+                    return Err(Diagnostic::error(
+                        format!("invalid digit `{}` in {} literal `{}`", c, kind, text),
+                        span,
+                    )
+                    .with_code("E0012"))
+                }
+                None => {
+                    return Err(Diagnostic::error(
+                        format!("invalid suffix `{}` on integer literal", &digits[i..]),
+                        span,
+                    )
+                    .with_code("E0012"))
+                }
+            };
+            value = (value * radix as u64 + digit as u64).min(u64::from(u32::MAX) + 1);
+        }
+        // `-2147483648` is lexed as `-` followed by `2147483648`; the literal
+        // alone does not fit in an int, so keep its bit pattern and let the
+        // negation wrap back to INT_MIN.
+        let negated = self.prev == Some(Tok::Minus);
+        let limit = if radix != 10 {
+            u64::from(u32::MAX)
+        } else if negated {
+            1 << 31
+        } else {
+            i32::MAX as u64
+        };
+        if value > limit {
+            let mut err = Diagnostic::error(
+                format!("integer literal `{}` is too large for type int", text),
+                span,
+            )
+            .with_code("E0012");
+            if radix == 10 && value == 1 << 31 {
+                err = err.with_note("`2147483648` is only allowed as the operand of unary `-`");
+            }
+            return Err(err);
+        }
+        Ok(Tok::IntConst(value as u32 as i32))
+    }
+
+    fn punct(&mut self) -> Result<Tok, Diagnostic> {
+        let two = match (self.peek(0), self.peek(1)) {
+            (b'<', b'=') => Some(Tok::Le),
+            (b'>', b'=') => Some(Tok::Ge),
+            (b'=', b'=') => Some(Tok::EqEq),
+            (b'!', b'=') => Some(Tok::Ne),
+            (b'&', b'&') => Some(Tok::AndAnd),
+            (b'|', b'|') => Some(Tok::OrOr),
+            _ => None,
+        };
+        if let Some(tok) = two {
+            self.pos += 2;
+            return Ok(tok);
+        }
+        let one = match self.peek(0) {
+            b'(' => Tok::LParen,
+            b')' => Tok::RParen,
+            b'{' => Tok::LBrace,
+            b'}' => Tok::RBrace,
+            b';' => Tok::Semi,
+            b',' => Tok::Comma,
+            b'=' => Tok::Assign,
+            b'+' => Tok::Plus,
+            b'-' => Tok::Minus,
+            b'*' => Tok::Star,
+            b'/' => Tok::Slash,
+            b'%' => Tok::Percent,
+            b'!' => Tok::Not,
+            b'<' => Tok::Lt,
+            b'>' => Tok::Gt,
+            _ => {
+                let c = self.src[self.pos..].chars().next().unwrap();
+                let start = self.pos;
+                self.pos += c.len_utf8();
+                return Err(Diagnostic::error(
+                    format!("unexpected character `{}`", c.escape_debug()),
+                    self.span(start, self.pos),
+                )
+                .with_code("E0013"));
+            }
+        };
+        self.pos += 1;
+        Ok(one)
+    }
+}
+
+impl<'a> Iterator for Lexer<'a> {
+    type Item = Spanned;
+
+    fn next(&mut self) -> Option<Spanned> {
+        self.skip_trivia();
+        if self.pos >= self.src.len() {
+            return None;
+        }
+        let start = self.pos;
+        let c = self.peek(0);
+        let tok = if c.is_ascii_alphabetic() || c == b'_' {
+            Ok(self.ident())
+        } else if c.is_ascii_digit() {
+            self.number()
+        } else {
+            self.punct()
+        };
+        self.prev = tok.as_ref().ok().cloned();
+        Some(tok.map(|tok| (self.base + start, tok, self.base + self.pos)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lex(src: &str) -> Vec<Result<Tok, String>> {
+        Lexer::new(src, 0)
+            .map(|tok| tok.map(|(_, tok, _)| tok).map_err(|err| err.message))
+            .collect()
+    }
+
+    #[test]
+    fn keywords_identifiers_and_operators() {
+        assert_eq!(
+            lex("int main_1 <= !x || 0x1F\n 017"),
+            vec![
+                Ok(Tok::Int),
+                Ok(Tok::Ident("main_1".to_string())),
+                Ok(Tok::Le),
+                Ok(Tok::Not),
+                Ok(Tok::Ident("x".to_string())),
+                Ok(Tok::OrOr),
+                Ok(Tok::IntConst(31)),
+                Ok(Tok::IntConst(15)),
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_literals_are_errors() {
+        for (src, message) in [
+            ("08", "invalid digit `8` in octal literal `08`"),
+            ("0x", "missing digits after the hexadecimal prefix `0x`"),
+            ("12abc", "invalid suffix `abc` on integer literal"),
+        ] {
+            assert_eq!(lex(src), vec![Err(message.to_string())], "{}", src);
+            let err = Lexer::new(src, 0).next().unwrap().unwrap_err();
+            assert_eq!(err.code, Some("E0012"), "{}", src);
+        }
+    }
+
+    #[test]
+    fn int_min_is_only_allowed_after_minus() {
+        assert_eq!(
+            lex("-2147483648"),
+            vec![Ok(Tok::Minus), Ok(Tok::IntConst(i32::MIN))]
+        );
+        assert_eq!(
+            lex("2147483648"),
+            vec![Err(
+                "integer literal `2147483648` is too large for type int".to_string()
+            )]
+        );
+        assert_eq!(lex("0xffffffff"), vec![Ok(Tok::IntConst(-1))]);
+    }
+
+    #[test]
+    fn lexing_resumes_after_an_error() {
+        let toks = lex("a $ b 08 c");
+        assert_eq!(toks.len(), 5);
+        assert_eq!(toks[0], Ok(Tok::Ident("a".to_string())));
+        assert_eq!(toks[1], Err("unexpected character `$`".to_string()));
+        assert_eq!(toks[2], Ok(Tok::Ident("b".to_string())));
+        assert_eq!(
+            toks[3],
+            Err("invalid digit `8` in octal literal `08`".to_string())
+        );
+        assert_eq!(toks[4], Ok(Tok::Ident("c".to_string())));
+    }
+}
@@ -0,0 +1,22 @@
+use lalrpop_util::lalrpop_mod;
+
+pub mod ast;
+pub mod ast2ir;
+pub mod debugger;
+pub mod diag;
+pub mod error_codes;
+pub mod interp;
+pub mod ir2riscv;
+pub mod lexer;
+pub mod lint;
+pub mod preprocess;
+pub mod repl;
+pub mod runtime;
+pub mod symbols;
+
+lalrpop_mod!(
+    #[allow(clippy::all, unused)]
+    pub sysy
+);
+
+pub use crate::sysy::CompUnitParser;
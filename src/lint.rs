@@ -0,0 +1,648 @@
+use crate::ast::*;
+use crate::ast2ir::{compute_expr, IdElement, IdTable};
+use crate::diag::{self, Diagnostic, Severity, Span};
+use crate::runtime::Runtime;
+use std::collections::HashSet;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Lint {
+    UnusedVariable,
+    UnusedParameter,
+    Uninitialized,
+    UnusedResult,
+    ReturnType,
+    UnreachableCode,
+}
+
+impl Lint {
+    pub const ALL: [Lint; 6] = [
+        Lint::UnusedVariable,
+        Lint::UnusedParameter,
+        Lint::Uninitialized,
+        Lint::UnusedResult,
+        Lint::ReturnType,
+        Lint::UnreachableCode,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Lint::UnusedVariable => "unused-variable",
+            Lint::UnusedParameter => "unused-parameter",
+            Lint::Uninitialized => "uninitialized",
+            Lint::UnusedResult => "unused-result",
+            Lint::ReturnType => "return-type",
+            Lint::UnreachableCode => "unreachable-code",
+        }
+    }
+
+    /// The option turning the warning on, which identifies it in
+    /// machine-readable output.
+    pub fn option(self) -> &'static str {
+        match self {
+            Lint::UnusedVariable => "-Wunused-variable",
+            Lint::UnusedParameter => "-Wunused-parameter",
+            Lint::Uninitialized => "-Wuninitialized",
+            Lint::UnusedResult => "-Wunused-result",
+            Lint::ReturnType => "-Wreturn-type",
+            Lint::UnreachableCode => "-Wunreachable-code",
+        }
+    }
+
+    pub fn from_option(option: &str) -> Option<Lint> {
+        Lint::ALL.into_iter().find(|l| l.option() == option)
+    }
+
+    /// What the warning is about, for the rules of a SARIF log.
+    pub fn summary(self) -> &'static str {
+        match self {
+            Lint::UnusedVariable => "local variable that is never read",
+            Lint::UnusedParameter => "parameter that is never read",
+            Lint::Uninitialized => "read of a variable that may not have been assigned",
+            Lint::UnusedResult => "ignored result of an `int` function",
+            Lint::ReturnType => "`int` function that can end without returning a value",
+            Lint::UnreachableCode => "statement that can never run",
+        }
+    }
+}
+
+/// Which warnings are reported and which of them are errors, set from the
+/// `-W` flags. Every warning is on by default.
+#[derive(Default)]
+pub struct Config {
+    disabled: HashSet<Lint>,
+    errors: HashSet<Lint>,
+}
+
+impl Config {
+    /// Applies one of `-w`, `-Wall`, `-Werror`, `-Werror=<name>`,
+    /// `-W<name>` or `-Wno-<name>`.
+    pub fn apply(&mut self, flag: &str) -> Result<(), String> {
+        let lint = |name: &str| {
+            Lint::ALL
+                .into_iter()
+                .find(|l| l.name() == name)
+                .ok_or_else(|| format!("unknown warning option `{}`", flag))
+        };
+        match flag {
+            "-w" => self.disabled.extend(Lint::ALL),
+            "-Wall" => self.disabled.clear(),
+            "-Werror" => self.errors.extend(Lint::ALL),
+            _ => {
+                let name = flag
+                    .strip_prefix("-W")
+                    .ok_or_else(|| format!("unknown warning option `{}`", flag))?;
+                if let Some(name) = name.strip_prefix("error=") {
+                    self.errors.insert(lint(name)?);
+                } else if let Some(name) = name.strip_prefix("no-error=") {
+                    self.errors.remove(&lint(name)?);
+                } else if let Some(name) = name.strip_prefix("no-") {
+                    self.disabled.insert(lint(name)?);
+                } else {
+                    self.disabled.remove(&lint(name)?);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Var {
+    name: String,
+    span: Span,
+    param: bool,
+    read: bool,
+    written: bool,
+    /// Set once a read before initialization was reported, so a variable
+    /// that is never assigned is only reported at its first use.
+    uninit_reported: bool,
+}
+
+/// Locals that may still be uninitialized at a program point, or `None` when
+/// the point cannot be reached.
+type State = Option<HashSet<usize>>;
+
+fn join(a: State, b: State) -> State {
+    match (a, b) {
+        (None, s) | (s, None) => s,
+        (Some(mut a), Some(b)) => {
+            a.extend(b);
+            Some(a)
+        }
+    }
+}
+
+/// What a name in scope refers to.
+#[derive(Debug, Clone, Copy)]
+enum Name {
+    /// A local or parameter, indexing `Checker::vars`.
+    Var(usize),
+    /// A global variable, which is not tracked.
+    Global,
+    /// A constant, with its value unless its initializer is invalid.
+    Const(Option<i32>),
+}
+
+struct Checker<'a> {
+    config: &'a Config,
+    /// Functions returning `int`, whose results should not be dropped.
+    int_funcs: HashSet<String>,
+    vars: Vec<Var>,
+    /// Names in scope, globals first and innermost last.
+    scopes: Vec<Vec<(String, Name)>>,
+    /// States at each `break` of the enclosing loops.
+    breaks: Vec<State>,
+    warnings: Vec<Diagnostic>,
+}
+
+/// Reports suspicious but valid code: locals and parameters that are never
+/// read, reads of variables that may not have been assigned yet, dropped
+/// results of `int` functions, `int` functions that can end without
+/// returning a value and statements that can never run.
+pub fn check(units: &[CompUnit], runtime: &Runtime, config: &Config) {
+    let mut int_funcs: HashSet<String> = runtime
+        .funcs()
+        .iter()
+        .filter(|f| f.kind == "i32")
+        .map(|f| f.name.clone())
+        .collect();
+    for unit in units.iter() {
+        for item in unit.list.iter() {
+            match item {
+                CompItem::FuncDef(FuncDef {
+                    func_type: FuncType::Int,
+                    ident,
+                    ..
+                })
+                | CompItem::FuncDecl(FuncDecl {
+                    func_type: FuncType::Int,
+                    ident,
+                    ..
+                }) => {
+                    int_funcs.insert(ident.clone());
+                }
+                _ => {}
+            }
+        }
+    }
+    let mut checker = Checker {
+        config,
+        int_funcs,
+        vars: Vec::new(),
+        scopes: vec![Vec::new()],
+        breaks: Vec::new(),
+        warnings: Vec::new(),
+    };
+    for unit in units.iter() {
+        for item in unit.list.iter() {
+            match item {
+                CompItem::ConstDecl(defs) => checker.consts(defs),
+                CompItem::VarDecl(defs) | CompItem::ExternDecl(defs) => {
+                    for def in defs.iter() {
+                        checker.scopes[0].push((def.id.clone(), Name::Global));
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    for unit in units.iter() {
+        for item in unit.list.iter() {
+            if let CompItem::FuncDef(func) = item {
+                checker.func(func);
+            }
+        }
+    }
+    checker.warnings.sort_by_key(|w| w.span.map(|s| s.lo));
+    for warning in checker.warnings {
+        diag::emit(warning);
+    }
+}
+
+impl<'a> Checker<'a> {
+    fn warn(&mut self, lint: Lint, message: String, span: Span) -> Option<&mut Diagnostic> {
+        if self.config.disabled.contains(&lint) {
+            return None;
+        }
+        let mut warning = Diagnostic::warning(message, Some(span)).with_lint(lint);
+        if self.config.errors.contains(&lint) {
+            warning.severity = Severity::Error;
+        }
+        self.warnings.push(warning);
+        self.warnings.last_mut()
+    }
+
+    fn lookup(&self, name: &str) -> Option<Name> {
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.iter().rev().find(|(n, _)| n == name))
+            .map(|(_, id)| *id)
+    }
+
+    fn consts(&mut self, defs: &[ConstDef]) {
+        for def in defs.iter() {
+            let value = self.fold(&def.value);
+            self.scopes
+                .last_mut()
+                .unwrap()
+                .push((def.id.clone(), Name::Const(value)));
+        }
+    }
+
+    /// The value of `expr` if it is a constant expression. Errors in it
+    /// are `ast2ir`'s to report, so they are dropped here.
+    fn fold(&self, expr: &Expr) -> Option<i32> {
+        let mut table = IdTable::new(None, 0);
+        for (name, binding) in self.scopes.iter().flatten() {
+            let element = match binding {
+                Name::Const(Some(value)) => IdElement::Const(*value),
+                _ => IdElement::Var(String::new()),
+            };
+            table.insert(name.clone(), element);
+        }
+        let earlier = diag::take();
+        let value = compute_expr(expr, &table);
+        let valid = diag::take().is_empty();
+        for diag in earlier {
+            diag::emit(diag);
+        }
+        value.ok().filter(|_| valid)
+    }
+
+    fn declare(&mut self, name: &str, span: Span, param: bool) -> usize {
+        self.vars.push(Var {
+            name: name.to_string(),
+            span,
+            param,
+            read: false,
+            written: param,
+            uninit_reported: false,
+        });
+        let id = self.vars.len() - 1;
+        self.scopes
+            .last_mut()
+            .unwrap()
+            .push((name.to_string(), Name::Var(id)));
+        id
+    }
+
+    fn pop_scope(&mut self) {
+        for (_, id) in self.scopes.pop().unwrap() {
+            let Name::Var(id) = id else { continue };
+            let var = &self.vars[id];
+            if var.read {
+                continue;
+            }
+            let (name, span) = (var.name.clone(), var.span);
+            if var.param {
+                self.warn(
+                    Lint::UnusedParameter,
+                    format!("unused parameter `{}`", name),
+                    span,
+                );
+            } else if var.written {
+                self.warn(
+                    Lint::UnusedVariable,
+                    format!("variable `{}` is assigned but never read", name),
+                    span,
+                );
+            } else {
+                self.warn(
+                    Lint::UnusedVariable,
+                    format!("unused variable `{}`", name),
+                    span,
+                );
+            }
+        }
+    }
+
+    fn func(&mut self, func: &FuncDef) {
+        self.scopes.push(Vec::new());
+        for param in func.params.iter() {
+            self.declare(&param.ident, param.span, true);
+        }
+        let end = self.block(&func.block, Some(HashSet::new()));
+        self.pop_scope();
+        // Like C99, `main` returns 0 when it runs off the end.
+        if end.is_some() && matches!(func.func_type, FuncType::Int) && func.ident != "main" {
+            if let Some(warning) = self.warn(
+                Lint::ReturnType,
+                format!("control reaches end of non-void function `{}`", func.ident),
+                func.span,
+            ) {
+                warning
+                    .notes
+                    .push("the function returns 0 on this path".to_string());
+            }
+        }
+    }
+
+    fn block(&mut self, block: &Block, mut state: State) -> State {
+        self.scopes.push(Vec::new());
+        let mut reported = false;
+        for item in block.items.iter() {
+            match item {
+                BlockItem::ConstDecl(defs) => {
+                    for def in defs.iter() {
+                        self.expr(&def.value, &mut state);
+                    }
+                    self.consts(defs);
+                }
+                BlockItem::VarDecl(defs) => {
+                    for def in defs.iter() {
+                        if let Some(value) = &def.value {
+                            self.expr(value, &mut state);
+                        }
+                        let id = self.declare(&def.id, def.span, false);
+                        if let (None, Some(uninit)) = (&def.value, &mut state) {
+                            uninit.insert(id);
+                        }
+                    }
+                }
+                BlockItem::Stmt(stmt, span) => {
+                    if state.is_none() && !reported && !matches!(stmt, Stmt::Expr(None)) {
+                        reported = true;
+                        self.warn(
+                            Lint::UnreachableCode,
+                            "unreachable statement".to_string(),
+                            *span,
+                        );
+                    }
+                    state = self.stmt(stmt, state);
+                }
+            }
+        }
+        self.pop_scope();
+        state
+    }
+
+    fn stmt(&mut self, stmt: &Stmt, mut state: State) -> State {
+        match stmt {
+            Stmt::Ret(e) => {
+                if let Some(e) = e {
+                    self.expr(e, &mut state);
+                }
+                None
+            }
+            Stmt::Assign(name, e, _) => {
+                self.expr(e, &mut state);
+                if let Some(Name::Var(id)) = self.lookup(name) {
+                    self.vars[id].written = true;
+                    if let Some(uninit) = &mut state {
+                        uninit.remove(&id);
+                    }
+                }
+                state
+            }
+            Stmt::Block(block) => self.block(block, state),
+            Stmt::Expr(e) => {
+                if let Some(e) = e {
+                    self.expr(e, &mut state);
+                    if let Expr::Func(name, _, span) = e {
+                        if self.lookup(name).is_none() && self.int_funcs.contains(name) {
+                            self.warn(
+                                Lint::UnusedResult,
+                                format!("result of call to `{}` is unused", name),
+                                *span,
+                            );
+                        }
+                    }
+                }
+                state
+            }
+            Stmt::IfElse(cond, then, otherwise) => {
+                self.expr(cond, &mut state);
+                let after_then = self.stmt(&then.0, state.clone());
+                let after_else = match otherwise {
+                    Some(otherwise) => self.stmt(&otherwise.0, state),
+                    None => state,
+                };
+                join(after_then, after_else)
+            }
+            Stmt::While(cond, body) => {
+                self.expr(cond, &mut state);
+                // Reads in the body are checked against the state on entry:
+                // later iterations can only have assigned more variables.
+                self.breaks.push(None);
+                self.stmt(&body.0, state.clone());
+                let breaks = self.breaks.pop().unwrap();
+                match self.fold(cond) {
+                    Some(n) if n != 0 => breaks,
+                    _ => join(state, breaks),
+                }
+            }
+            Stmt::Break(_) => {
+                // Outside a loop this is an error reported by `ast2ir`.
+                if let Some(top) = self.breaks.last_mut() {
+                    *top = join(top.take(), state);
+                }
+                None
+            }
+            Stmt::Continue(_) => None,
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr, state: &mut State) {
+        match expr {
+            Expr::Number(_) => {}
+            Expr::UnaryExpr(_, e) => self.expr(e, state),
+            Expr::BinaryExpr(lhs, _, rhs, _) => {
+                self.expr(lhs, state);
+                self.expr(rhs, state);
+            }
+            Expr::LVal(name, span) => {
+                let Some(Name::Var(id)) = self.lookup(name) else {
+                    return;
+                };
+                self.vars[id].read = true;
+                let Some(uninit) = state else { return };
+                if uninit.remove(&id) && !self.vars[id].uninit_reported {
+                    self.vars[id].uninit_reported = true;
+                    if let Some(warning) = self.warn(
+                        Lint::Uninitialized,
+                        format!("`{}` may be used uninitialized", name),
+                        *span,
+                    ) {
+                        warning.notes.push(format!(
+                            "`{}` is declared without an initializer and may not be assigned on every path to here",
+                            name
+                        ));
+                    }
+                }
+            }
+            Expr::Func(_, args, _) => {
+                for arg in args.iter() {
+                    self.expr(arg, state);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+
+    /// Lints `src` with the given flags, returning the severity and message
+    /// of each warning. Checks that each names the option controlling it.
+    fn lint(src: &str, flags: &[&str]) -> Vec<(Severity, String)> {
+        let unit = crate::sysy::CompUnitParser::new()
+            .parse(Lexer::new(src, 0))
+            .unwrap();
+        let mut config = Config::default();
+        for flag in flags {
+            config.apply(flag).unwrap();
+        }
+        check(&[unit], &Runtime::default(), &config);
+        diag::take()
+            .into_iter()
+            .map(|d| {
+                assert_eq!(d.rule(), Some(d.lint.unwrap().option()));
+                (d.severity, d.message)
+            })
+            .collect()
+    }
+
+    fn warning(message: &str) -> (Severity, String) {
+        (Severity::Warning, message.to_string())
+    }
+
+    #[test]
+    fn unused_locals_and_parameters() {
+        assert_eq!(
+            lint(
+                "int f(int p, int q) { int a; int b = 1; b = 2; return q; }",
+                &[]
+            ),
+            vec![
+                warning("unused parameter `p`"),
+                warning("unused variable `a`"),
+                warning("variable `b` is assigned but never read"),
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_before_assignment() {
+        assert_eq!(
+            lint(
+                "int f(int c) { int a; if (c) a = 1; return a; }",
+                &["-Wno-unused-variable"]
+            ),
+            vec![warning("`a` may be used uninitialized")]
+        );
+        assert_eq!(
+            lint(
+                "int f(int c) { int a; if (c) a = 1; else a = 2; return a; }",
+                &[]
+            ),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn dropped_results() {
+        assert_eq!(
+            lint(
+                "int g() { return 1; } void h() {} int main() { g(); h(); return 0; }",
+                &[]
+            ),
+            vec![warning("result of call to `g` is unused")]
+        );
+    }
+
+    #[test]
+    fn missing_returns() {
+        assert_eq!(
+            lint("int f(int c) { if (c) return 1; } int main() {}", &[]),
+            vec![warning("control reaches end of non-void function `f`")]
+        );
+        assert_eq!(
+            lint("int f(int c) { if (c) return 1; else return 2; }", &[]),
+            vec![]
+        );
+        assert_eq!(lint("int f() { while (1) {} }", &[]), vec![]);
+        assert_eq!(lint("int f() { while (1 + 0) {} }", &[]), vec![]);
+        assert_eq!(
+            lint(
+                "const int N = 2; int f() { const int M = N - 1; while (M) {} }",
+                &[]
+            ),
+            vec![]
+        );
+        assert_eq!(
+            lint("const int N = 0; int f() { while (N) {} }", &[]),
+            vec![warning("control reaches end of non-void function `f`")]
+        );
+        assert_eq!(
+            lint("int f(int n) { while (n / 0) {} }", &[]),
+            vec![warning("control reaches end of non-void function `f`")]
+        );
+    }
+
+    #[test]
+    fn unreachable_statements() {
+        assert_eq!(
+            lint(
+                "int f() { return 1; ; f(); f(); } void g() { while (1) { break; g(); } }",
+                &["-Wno-unused-result"]
+            ),
+            vec![
+                warning("unreachable statement"),
+                warning("unreachable statement"),
+            ]
+        );
+    }
+
+    #[test]
+    fn flags_disable_and_escalate() {
+        let src = "int f(int p) { int a; return 0; }";
+        assert_eq!(lint(src, &["-w"]), vec![]);
+        assert_eq!(lint(src, &["-w", "-Wall"]).len(), 2);
+        assert_eq!(
+            lint(src, &["-Wno-unused-variable", "-Werror=unused-parameter"]),
+            vec![(Severity::Error, "unused parameter `p`".to_string())]
+        );
+        assert!(lint(src, &["-Werror"])
+            .iter()
+            .all(|(severity, _)| *severity == Severity::Error));
+        assert!(lint(src, &["-Werror", "-Wno-error=unused-variable"])
+            .contains(&warning("unused variable `a`")));
+    }
+
+    #[test]
+    fn options_name_warnings() {
+        let mut source_map = diag::SourceMap::default();
+        let src = "int f(int p) { return 0; }";
+        let base = source_map.add("a.c", src);
+        let unit = crate::sysy::CompUnitParser::new()
+            .parse(Lexer::new(src, base))
+            .unwrap();
+        check(&[unit], &Runtime::default(), &Config::default());
+        let warnings = diag::take();
+        assert!(source_map
+            .render(&warnings[0])
+            .starts_with("warning: unused parameter `p` [-Wunused-parameter]\n"));
+        let json = source_map.to_json(&warnings[0]);
+        assert_eq!(json["code"], "-Wunused-parameter");
+        assert_eq!(json["message"], "unused parameter `p`");
+    }
+
+    #[test]
+    fn unknown_flags_are_rejected() {
+        let mut config = Config::default();
+        assert_eq!(
+            config.apply("-Wshadow"),
+            Err("unknown warning option `-Wshadow`".to_string())
+        );
+        assert_eq!(
+            config.apply("-Wno-error=foo"),
+            Err("unknown warning option `-Wno-error=foo`".to_string())
+        );
+        assert_eq!(
+            config.apply("-x"),
+            Err("unknown warning option `-x`".to_string())
+        );
+    }
+}
@@ -1,38 +1,346 @@
-use lalrpop_util::lalrpop_mod;
+use std::collections::HashSet;
 use std::env::args;
-use std::fs::{self, read_to_string};
-use std::io::Result;
+use std::fs;
+use std::io::{self, Result};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::sync::Mutex;
+use std::thread;
+use sysy::{
+    ast2ir, debugger, diag, error_codes, interp, ir2riscv, lexer, lint, preprocess, repl, runtime,
+};
 
-mod ast;
-mod ast2ir;
-mod ir2riscv;
-
-lalrpop_mod!(sysy);
+/// Internal compiler errors caught by the panic hook, for the report.
+static ICES: Mutex<Vec<diag::Diagnostic>> = Mutex::new(Vec::new());
 
 fn main() -> Result<()> {
     let mut args = args();
     args.next();
     let mode = args.next().unwrap();
-    let input = args.next().unwrap();
-    args.next();
-    let outfile = args.next().unwrap();
-    let input = read_to_string(input)?;
-    let mut ast = sysy::CompUnitParser::new().parse(&input).unwrap();
-    println!("{:#?}", ast);
-    let koopa_ir;
-    if mode == "-koopa" {
-        koopa_ir = ast2ir::ast2ir(&mut ast);
-        let driver = koopa::front::Driver::from(koopa_ir.clone());
-        driver.generate_program().unwrap();
-        fs::write(outfile, koopa_ir)?;
+    if mode == "--explain" {
+        let code = args.next().unwrap_or_default();
+        match error_codes::explain(&code) {
+            Some(text) => print!("{}", text),
+            None => {
+                eprintln!("error: `{}` is not a valid error code", code);
+                exit(1);
+            }
+        }
         return Ok(());
     }
-    let riscv;
+    let mut inputs = Vec::new();
+    let mut outfile = None;
+    let mut runtime_specs = Vec::new();
+    let mut warnings = lint::Config::default();
+    let mut format = diag::Format::Human;
+    while let Some(arg) = args.next() {
+        if arg == "-o" {
+            outfile = args.next();
+        } else if arg == "-runtime" {
+            runtime_specs.push(args.next().unwrap());
+        } else if let Some(name) = arg.strip_prefix("--diagnostic-format=") {
+            format = match name {
+                "human" => diag::Format::Human,
+                "json" => diag::Format::Json,
+                "sarif" => diag::Format::Sarif,
+                _ => {
+                    eprintln!("error: unknown diagnostic format `{}`", name);
+                    exit(1);
+                }
+            };
+        } else if arg == "-w" || arg.starts_with("-W") {
+            if let Err(msg) = warnings.apply(&arg) {
+                eprint!(
+                    "{}",
+                    diag::SourceMap::default().render(&diag::Diagnostic::error(msg, None))
+                );
+                exit(1);
+            }
+        } else {
+            inputs.push(arg);
+        }
+    }
+    let outfile = match outfile {
+        Some(outfile) => outfile,
+        // Interpreted programs write to stdout.
+        None if mode == "-run" || mode == "-debug" || mode == "-repl" => String::new(),
+        None => {
+            eprintln!("error: no output file, use `-o <file>`");
+            exit(1);
+        }
+    };
+    if format != diag::Format::Human {
+        // Keep stderr machine-readable even if the compiler itself fails:
+        // the panic becomes one more diagnostic of the report.
+        panic::set_hook(Box::new(|info| {
+            let payload = info.payload();
+            let message = payload
+                .downcast_ref::<&str>()
+                .copied()
+                .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+                .unwrap_or("unknown panic");
+            let mut err =
+                diag::Diagnostic::error(format!("internal compiler error: {}", message), None);
+            if let Some(location) = info.location() {
+                err = err.with_note(format!("panicked at {}", location));
+            }
+            ICES.lock().unwrap().push(err);
+        }));
+    }
+    let mut source_map = diag::SourceMap::default();
+    let mut reporter = Reporter::new(format);
+    let options = Options {
+        mode,
+        inputs,
+        outfile,
+        runtime_specs,
+        warnings,
+    };
+    let code = match panic::catch_unwind(AssertUnwindSafe(|| {
+        compile(&options, &mut source_map, &mut reporter)
+    })) {
+        Ok(Ok(code)) => code,
+        Ok(Err(err)) => {
+            let err = diag::Diagnostic::error(format!("I/O error: {}", err), None);
+            reporter.report(&source_map, vec![err]);
+            1
+        }
+        Err(_) => 101,
+    };
+    let ices = std::mem::take(&mut *ICES.lock().unwrap());
+    reporter.report(&source_map, ices);
+    reporter.finish(&source_map);
+    exit(code)
+}
+
+struct Options {
+    mode: String,
+    inputs: Vec<String>,
+    outfile: String,
+    runtime_specs: Vec<String>,
+    warnings: lint::Config,
+}
+
+/// Runs the compiler as `options` ask. Returns the exit code.
+fn compile(
+    options: &Options,
+    source_map: &mut diag::SourceMap,
+    reporter: &mut Reporter,
+) -> Result<i32> {
+    let Options {
+        mode,
+        inputs,
+        outfile,
+        runtime_specs,
+        warnings,
+    } = options;
+    let mut runtime = runtime::Runtime::default();
+    for spec in runtime_specs.iter() {
+        if let Err(err) = runtime.load(spec, source_map) {
+            reporter.report(source_map, vec![err]);
+            return Ok(1);
+        }
+    }
+    if mode == "-repl" {
+        on_interp_stack(|| repl::run(&runtime, source_map))??;
+        return Ok(0);
+    }
+    let mut out = String::new();
+    let mut units = Vec::new();
+    for input in inputs.iter() {
+        let source = match preprocess::preprocess(input, source_map) {
+            Ok(source) => source,
+            Err(err) => {
+                reporter.report(source_map, vec![err]);
+                return Ok(1);
+            }
+        };
+        if mode == "-E" {
+            out += &source;
+            continue;
+        }
+        let base = source_map.add(input, &source);
+        if mode == "-tokens" {
+            let mut errors = Vec::new();
+            for tok in lexer::Lexer::new(&source, base) {
+                match tok {
+                    Ok((lo, tok, hi)) => {
+                        let loc = source_map.lookup(lo);
+                        out += &format!(
+                            "{}:{}:{}\t{:?}\t`{}`\n",
+                            loc.file,
+                            loc.line,
+                            loc.col,
+                            tok,
+                            &source[lo - base..hi - base]
+                        );
+                    }
+                    Err(err) => errors.push(err),
+                }
+            }
+            if !errors.is_empty() {
+                // The tokens around the errors are still worth seeing.
+                fs::write(outfile, &out)?;
+                reporter.report(source_map, errors);
+                return Ok(1);
+            }
+            continue;
+        }
+        match sysy::CompUnitParser::new().parse(lexer::Lexer::new(&source, base)) {
+            Ok(ast) => units.push(ast),
+            Err(err) => {
+                reporter.report(source_map, vec![err.into()]);
+                return Ok(1);
+            }
+        }
+    }
+    if mode == "-E" || mode == "-tokens" {
+        fs::write(outfile, out)?;
+        return Ok(0);
+    }
+    let modules = ast2ir::ast2ir(&mut units, &runtime, source_map);
+    lint::check(&units, &runtime, warnings);
+    if reporter.report(source_map, diag::take()) {
+        return Ok(1);
+    }
+    let source_map = &*source_map;
+    if mode == "-run" || mode == "-debug" {
+        let result = on_interp_stack(|| {
+            let input = Box::new(io::stdin().lock());
+            let output = Box::new(io::BufWriter::new(io::stdout()));
+            let mut interp = interp::Interpreter::new(&units, input, output)?;
+            if mode == "-debug" {
+                let debugger = debugger::Debugger::new(source_map, &inputs[0]);
+                interp.set_hook(Box::new(debugger));
+            }
+            interp.run_main()
+        })?;
+        return match result {
+            Ok(code) => {
+                if mode == "-debug" {
+                    println!("Program exited with code {}", code);
+                }
+                Ok(code & 0xff)
+            }
+            Err(err) => {
+                reporter.report(source_map, vec![err]);
+                Ok(1)
+            }
+        };
+    }
+    if mode == "-koopa" {
+        // Several modules arise from several files or from mutual recursion.
+        // Each is a complete Koopa program defining its own copy of the
+        // globals, so they cannot share a file.
+        for (i, module) in modules.iter().enumerate() {
+            if let Err(err) = ir2riscv::parse_koopa(&module.koopa) {
+                reporter.report(source_map, vec![err]);
+                return Ok(1);
+            }
+            fs::write(module_path(outfile, i), &module.koopa)?;
+        }
+        return Ok(0);
+    }
     if mode == "-riscv" {
-        koopa_ir = ast2ir::ast2ir(&mut ast);
-        riscv = ir2riscv::ir2riscv(koopa_ir);
-        fs::write(outfile, riscv)?;
-        return Ok(());
+        let asm = || {
+            let mut written = HashSet::new();
+            for module in modules.iter() {
+                written.extend(ir2riscv::written_globals(&module.koopa)?);
+            }
+            modules
+                .into_iter()
+                .map(|module| {
+                    ir2riscv::ir2riscv(module.koopa, &module.externs, &written, &module.symbols)
+                })
+                .collect::<std::result::Result<String, diag::Diagnostic>>()
+        };
+        match asm() {
+            Ok(asm) => fs::write(outfile, asm)?,
+            Err(err) => {
+                reporter.report(source_map, vec![err]);
+                return Ok(1);
+            }
+        }
+    }
+    Ok(0)
+}
+
+/// Where module `i` of the Koopa output goes: `outfile` for the first,
+/// `a.1.koopa`, `a.2.koopa`, ... for the others if `outfile` is `a.koopa`.
+fn module_path(outfile: &str, i: usize) -> PathBuf {
+    let path = Path::new(outfile);
+    if i == 0 {
+        return path.to_path_buf();
+    }
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    match path.extension() {
+        Some(ext) => path.with_file_name(format!("{}.{}.{}", stem, i, ext.to_string_lossy())),
+        None => path.with_file_name(format!("{}.{}", stem, i)),
+    }
+}
+
+/// Runs `f` on a thread with room for the interpreter. SysY recursion
+/// becomes recursion in the interpreter, which needs a stack as deep as
+/// `MAX_CALL_DEPTH` calls.
+fn on_interp_stack<T: Send>(f: impl FnOnce() -> T + Send) -> Result<T> {
+    thread::scope(|scope| {
+        let handle = thread::Builder::new()
+            .stack_size(interp::STACK_SIZE)
+            .spawn_scoped(scope, f)?;
+        // A panic goes on in this thread, to be reported like any other.
+        Ok(handle
+            .join()
+            .unwrap_or_else(|payload| panic::resume_unwind(payload)))
+    })
+}
+
+/// Prints diagnostics to stderr. Rendered text and JSON lines are written
+/// as diagnostics are found; a SARIF log holds every diagnostic of the run
+/// and is written by `finish`.
+struct Reporter {
+    format: diag::Format,
+    sarif: Vec<diag::Diagnostic>,
+}
+
+impl Reporter {
+    fn new(format: diag::Format) -> Self {
+        Reporter {
+            format,
+            sarif: Vec::new(),
+        }
+    }
+
+    /// Reports `diagnostics` and tells whether any of them is an error.
+    fn report(&mut self, source_map: &diag::SourceMap, diagnostics: Vec<diag::Diagnostic>) -> bool {
+        let failed = diagnostics
+            .iter()
+            .any(|d| d.severity == diag::Severity::Error);
+        match self.format {
+            diag::Format::Human => {
+                for diagnostic in diagnostics.iter() {
+                    eprint!("{}", source_map.render(diagnostic));
+                }
+                if let Some(code) = diagnostics.iter().find_map(|d| d.code) {
+                    eprintln!(
+                        "For more information about an error, try `--explain {}`.",
+                        code
+                    );
+                }
+            }
+            diag::Format::Json => {
+                for diagnostic in diagnostics.iter() {
+                    eprintln!("{}", source_map.to_json(diagnostic));
+                }
+            }
+            diag::Format::Sarif => self.sarif.extend(diagnostics),
+        }
+        failed
+    }
+
+    fn finish(self, source_map: &diag::SourceMap) {
+        if self.format == diag::Format::Sarif {
+            eprintln!("{}", source_map.to_sarif(&self.sarif));
+        }
     }
-    Ok(())
 }
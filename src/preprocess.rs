@@ -0,0 +1,656 @@
+use crate::diag::{Diagnostic, SourceMap, Span};
+use std::collections::{HashMap, HashSet};
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+
+/// The runtime functions are predeclared by the compiler, so the header the
+/// SysY test suites include needs no contents of its own.
+const SYLIB_H: &str = "sylib.h";
+const MAX_INCLUDE_DEPTH: usize = 64;
+
+struct Macro {
+    params: Option<Vec<String>>,
+    body: String,
+}
+
+/// Lines of code to macro-expand together: a function-like macro
+/// invocation can go on over several lines.
+struct Code {
+    text: String,
+    /// The line the code starts on, for `__LINE__`.
+    line_no: usize,
+    span: Span,
+    /// How many physical lines the code takes.
+    count: usize,
+}
+
+struct Cond {
+    active: bool,
+    seen_else: bool,
+    span: Option<Span>,
+}
+
+/// Expands `#include`, `#define` and the `#ifdef` family in front of the
+/// parser. The output carries `# <line> "<file>"` markers so that positions
+/// in it can be traced back to the original files through the `SourceMap`.
+pub struct Preprocessor<'a> {
+    source_map: &'a mut SourceMap,
+    macros: HashMap<String, Macro>,
+    include_stack: Vec<PathBuf>,
+    out: String,
+}
+
+pub fn preprocess(path: &str, source_map: &mut SourceMap) -> Result<String, Diagnostic> {
+    let text = read_to_string(path).map_err(|e| {
+        Diagnostic::error(format!("cannot read `{}`: {}", path, e), None).with_code("E0018")
+    })?;
+    preprocess_source(path, &text, source_map)
+}
+
+/// Like `preprocess`, for the unsaved contents `text` of the file at `path`.
+/// Includes are still read from disk.
+pub fn preprocess_source(
+    path: &str,
+    text: &str,
+    source_map: &mut SourceMap,
+) -> Result<String, Diagnostic> {
+    let mut pp = Preprocessor {
+        source_map,
+        macros: HashMap::new(),
+        include_stack: Vec::new(),
+        out: String::new(),
+    };
+    pp.file(Path::new(path), text)?;
+    Ok(pp.out)
+}
+
+fn is_ident_start(c: u8) -> bool {
+    c.is_ascii_alphabetic() || c == b'_'
+}
+
+fn is_ident_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'_'
+}
+
+/// Splits `text` into logical lines, joining lines that end in a backslash.
+/// Each entry is `(offset, number of physical lines, text)`.
+fn logical_lines(text: &str) -> Vec<(usize, usize, String)> {
+    let mut lines = Vec::new();
+    let mut offset = 0;
+    let mut current: Option<(usize, usize, String)> = None;
+    for raw in text.split_inclusive('\n') {
+        let line = raw.trim_end_matches(['\n', '\r']);
+        let (start, count, mut acc) = current.take().unwrap_or((offset, 0, String::new()));
+        offset += raw.len();
+        match line.strip_suffix('\\') {
+            Some(head) => {
+                acc += head;
+                current = Some((start, count + 1, acc));
+            }
+            None => {
+                acc += line;
+                lines.push((start, count + 1, acc));
+            }
+        }
+    }
+    if let Some(line) = current {
+        lines.push(line);
+    }
+    lines
+}
+
+/// Replaces comments with spaces. `open_comment` carries the position of a
+/// block comment that is still open across lines; `pos` is where `line`
+/// starts.
+fn strip_comments(line: &str, open_comment: &mut Option<usize>, pos: usize) -> String {
+    let bytes = line.as_bytes();
+    let mut out = String::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        if open_comment.is_some() {
+            if bytes[i] == b'*' && bytes.get(i + 1) == Some(&b'/') {
+                *open_comment = None;
+                out.push_str("  ");
+                i += 2;
+            } else {
+                out.push(' ');
+                i += 1;
+            }
+        } else if bytes[i] == b'/' && bytes.get(i + 1) == Some(&b'*') {
+            *open_comment = Some(pos + i);
+            out.push_str("  ");
+            i += 2;
+        } else if bytes[i] == b'/' && bytes.get(i + 1) == Some(&b'/') {
+            break;
+        } else {
+            let c = line[i..].chars().next().unwrap();
+            out.push(c);
+            i += c.len_utf8();
+        }
+    }
+    out
+}
+
+impl<'a> Preprocessor<'a> {
+    fn file(&mut self, path: &Path, text: &str) -> Result<(), Diagnostic> {
+        let name = path.display().to_string();
+        let base = self.source_map.add(&name, text);
+        self.include_stack.push(path.to_path_buf());
+        self.out += &format!("# 1 \"{}\"\n", name);
+        let mut conds: Vec<Cond> = Vec::new();
+        let mut open_comment = None;
+        let mut line_no = 1;
+        let mut pending: Option<Code> = None;
+        for (offset, count, line) in logical_lines(text) {
+            let active = conds.iter().all(|c| c.active);
+            let in_comment = open_comment.is_some();
+            let code = strip_comments(&line, &mut open_comment, base + offset);
+            let directive = code.trim_start();
+            if !in_comment && directive.starts_with('#') {
+                if let Some(code) = pending.take() {
+                    // Reports the unterminated argument list.
+                    self.emit(code)?;
+                }
+                let span = Some(Span::new(base + offset, base + offset + line.len()));
+                let included = self.directive(&directive[1..], span, path, active, &mut conds)?;
+                if included {
+                    self.out += &format!("# {} \"{}\"\n", line_no + count, name);
+                } else {
+                    self.out += &"\n".repeat(count);
+                }
+            } else if active {
+                let span = Span::new(base + offset, base + offset + line.len());
+                let code = match pending.take() {
+                    Some(prev) => Code {
+                        text: prev.text + " " + &code,
+                        span: Span::new(prev.span.lo, span.hi),
+                        count: prev.count + count,
+                        ..prev
+                    },
+                    None => Code {
+                        text: code,
+                        line_no,
+                        span,
+                        count,
+                    },
+                };
+                if self.open_invocation(&code.text) {
+                    pending = Some(code);
+                } else {
+                    self.emit(code)?;
+                }
+            } else {
+                self.out += &"\n".repeat(count);
+            }
+            line_no += count;
+        }
+        if let Some(code) = pending {
+            self.emit(code)?;
+        }
+        if let Some(pos) = open_comment {
+            return Err(Diagnostic::error(
+                "unterminated block comment",
+                Some(Span::new(pos, pos + 2)),
+            )
+            .with_code("E0014")
+            .with_note("the comment starting here is never closed by `*/`"));
+        }
+        if let Some(cond) = conds.pop() {
+            return Err(
+                Diagnostic::error("unterminated conditional directive", cond.span)
+                    .with_code("E0015")
+                    .with_note("add a matching `#endif`"),
+            );
+        }
+        self.include_stack.pop();
+        Ok(())
+    }
+
+    /// Handles one directive line (without the leading `#`). Returns whether
+    /// a file was included, in which case a line marker has to follow.
+    fn directive(
+        &mut self,
+        line: &str,
+        span: Option<Span>,
+        path: &Path,
+        active: bool,
+        conds: &mut Vec<Cond>,
+    ) -> Result<bool, Diagnostic> {
+        let line = line.trim();
+        let (name, rest) = match line.find(|c: char| !c.is_ascii_alphanumeric() && c != '_') {
+            Some(i) => (&line[..i], line[i..].trim()),
+            None => (line, ""),
+        };
+        match name {
+            "ifdef" | "ifndef" => {
+                let defined = self.macros.contains_key(self.macro_name(rest, span)?);
+                conds.push(Cond {
+                    active: defined == (name == "ifdef"),
+                    seen_else: false,
+                    span,
+                });
+                return Ok(false);
+            }
+            "else" => {
+                let cond = match conds.last_mut() {
+                    Some(cond) if !cond.seen_else => cond,
+                    Some(_) => {
+                        return Err(
+                            Diagnostic::error("`#else` after `#else`", span).with_code("E0015")
+                        )
+                    }
+                    None => {
+                        return Err(
+                            Diagnostic::error("`#else` without `#ifdef`", span).with_code("E0015")
+                        )
+                    }
+                };
+                cond.active = !cond.active;
+                cond.seen_else = true;
+                return Ok(false);
+            }
+            "endif" => {
+                if conds.pop().is_none() {
+                    return Err(
+                        Diagnostic::error("`#endif` without `#ifdef`", span).with_code("E0015")
+                    );
+                }
+                return Ok(false);
+            }
+            _ => {}
+        }
+        if !active {
+            return Ok(false);
+        }
+        match name {
+            "" | "pragma" => Ok(false),
+            "include" => {
+                self.include(rest, span, path)?;
+                Ok(true)
+            }
+            "define" => {
+                self.define(rest, span)?;
+                Ok(false)
+            }
+            "undef" => {
+                let name = self.macro_name(rest, span)?.to_string();
+                self.macros.remove(&name);
+                Ok(false)
+            }
+            _ => Err(Diagnostic::error(
+                format!("unsupported preprocessor directive `#{}`", name),
+                span,
+            )
+            .with_code("E0015")
+            .with_note("supported directives are #include, #define, #undef, #ifdef, #ifndef, #else and #endif")),
+        }
+    }
+
+    fn macro_name<'l>(&self, rest: &'l str, span: Option<Span>) -> Result<&'l str, Diagnostic> {
+        let bytes = rest.as_bytes();
+        if bytes.is_empty() || !is_ident_start(bytes[0]) || !bytes.iter().all(|&c| is_ident_char(c))
+        {
+            return Err(
+                Diagnostic::error("macro name must be an identifier", span).with_code("E0015")
+            );
+        }
+        Ok(rest)
+    }
+
+    fn include(&mut self, rest: &str, span: Option<Span>, path: &Path) -> Result<(), Diagnostic> {
+        let file = if let Some(name) = rest.strip_prefix('"').and_then(|r| r.strip_suffix('"')) {
+            name
+        } else if let Some(name) = rest.strip_prefix('<').and_then(|r| r.strip_suffix('>')) {
+            name
+        } else {
+            return Err(
+                Diagnostic::error("`#include` expects \"FILENAME\" or <FILENAME>", span)
+                    .with_code("E0015"),
+            );
+        };
+        if file == SYLIB_H {
+            return Ok(());
+        }
+        if self.include_stack.len() >= MAX_INCLUDE_DEPTH {
+            return Err(Diagnostic::error(
+                format!("`#include` nested too deeply while including `{}`", file),
+                span,
+            )
+            .with_code("E0015")
+            .with_note("is a header including itself without an include guard?"));
+        }
+        let target = path.parent().unwrap_or(Path::new("")).join(file);
+        let text = read_to_string(&target).map_err(|e| {
+            Diagnostic::error(
+                format!("cannot include `{}`: {}", target.display(), e),
+                span,
+            )
+            .with_code("E0018")
+        })?;
+        self.file(&target, &text)
+    }
+
+    fn define(&mut self, rest: &str, span: Option<Span>) -> Result<(), Diagnostic> {
+        let end = rest
+            .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+            .unwrap_or(rest.len());
+        let name = self.macro_name(&rest[..end], span)?.to_string();
+        let rest = &rest[end..];
+        let (params, body) = match rest.strip_prefix('(') {
+            Some(list) => {
+                let close = list.find(')').ok_or_else(|| {
+                    Diagnostic::error("missing `)` in macro parameter list", span)
+                        .with_code("E0015")
+                })?;
+                let mut params = Vec::new();
+                for param in list[..close].split(',').map(str::trim) {
+                    if param.is_empty() && params.is_empty() && list[..close].trim().is_empty() {
+                        break;
+                    }
+                    let param = self.macro_name(param, span)?.to_string();
+                    if params.contains(&param) {
+                        return Err(Diagnostic::error(
+                            format!("duplicate macro parameter `{}`", param),
+                            span,
+                        )
+                        .with_code("E0015"));
+                    }
+                    params.push(param);
+                }
+                (Some(params), list[close + 1..].trim())
+            }
+            None => (None, rest.trim()),
+        };
+        if name == "__LINE__" {
+            return Err(Diagnostic::error("cannot redefine `__LINE__`", span).with_code("E0015"));
+        }
+        self.macros.insert(
+            name,
+            Macro {
+                params,
+                body: body.to_string(),
+            },
+        );
+        Ok(())
+    }
+
+    /// Macro-expands `code` into the output. It stays on its first line,
+    /// followed by blank lines for the others it took.
+    fn emit(&mut self, code: Code) -> Result<(), Diagnostic> {
+        let expanded = self.expand(&code.text, &HashSet::new(), code.line_no, Some(code.span))?;
+        self.out += &expanded;
+        self.out += &"\n".repeat(code.count);
+        Ok(())
+    }
+
+    /// Whether `text` ends inside the argument list of a function-like
+    /// macro, so that the invocation goes on on the next line.
+    fn open_invocation(&self, text: &str) -> bool {
+        let bytes = text.as_bytes();
+        let mut i = 0;
+        while i < bytes.len() {
+            if !is_ident_char(bytes[i]) {
+                i += 1;
+                continue;
+            }
+            let start = i;
+            while i < bytes.len() && is_ident_char(bytes[i]) {
+                i += 1;
+            }
+            let function_like = matches!(
+                self.macros.get(&text[start..i]),
+                Some(Macro {
+                    params: Some(_),
+                    ..
+                })
+            );
+            if !is_ident_start(bytes[start]) || !function_like {
+                continue;
+            }
+            let mut j = i;
+            while j < bytes.len() && bytes[j].is_ascii_whitespace() {
+                j += 1;
+            }
+            if bytes.get(j) == Some(&b'(') {
+                match split_args(text, j + 1) {
+                    Some((_, end)) => i = end,
+                    None => return true,
+                }
+            }
+        }
+        false
+    }
+
+    /// Macro-expands one line of code. `hidden` holds the macros currently
+    /// being expanded, which must not expand again inside their own bodies.
+    fn expand(
+        &self,
+        text: &str,
+        hidden: &HashSet<String>,
+        line_no: usize,
+        span: Option<Span>,
+    ) -> Result<String, Diagnostic> {
+        let bytes = text.as_bytes();
+        let mut out = String::new();
+        let mut i = 0;
+        while i < bytes.len() {
+            let c = bytes[i];
+            if c.is_ascii_digit() {
+                let start = i;
+                while i < bytes.len() && is_ident_char(bytes[i]) {
+                    i += 1;
+                }
+                out += &text[start..i];
+                continue;
+            }
+            if !is_ident_start(c) {
+                let c = text[i..].chars().next().unwrap();
+                out.push(c);
+                i += c.len_utf8();
+                continue;
+            }
+            let start = i;
+            while i < bytes.len() && is_ident_char(bytes[i]) {
+                i += 1;
+            }
+            let ident = &text[start..i];
+            if ident == "__LINE__" {
+                out += &line_no.to_string();
+                continue;
+            }
+            let mac = match self.macros.get(ident) {
+                Some(mac) if !hidden.contains(ident) => mac,
+                _ => {
+                    out += ident;
+                    continue;
+                }
+            };
+            let mut inner = hidden.clone();
+            inner.insert(ident.to_string());
+            let body = match &mac.params {
+                None => mac.body.clone(),
+                Some(params) => {
+                    let mut j = i;
+                    while j < bytes.len() && bytes[j].is_ascii_whitespace() {
+                        j += 1;
+                    }
+                    if bytes.get(j) != Some(&b'(') {
+                        // A function-like macro name without arguments is
+                        // left alone, as in C.
+                        out += ident;
+                        continue;
+                    }
+                    let (args, end) = split_args(text, j + 1).ok_or_else(|| {
+                        Diagnostic::error(
+                            format!("unterminated argument list invoking macro `{}`", ident),
+                            span,
+                        )
+                        .with_code("E0015")
+                    })?;
+                    i = end;
+                    let args = if params.is_empty() && args.len() == 1 && args[0].trim().is_empty()
+                    {
+                        Vec::new()
+                    } else {
+                        args
+                    };
+                    if args.len() != params.len() {
+                        return Err(Diagnostic::error(
+                            format!(
+                                "macro `{}` takes {} argument(s) but {} were given",
+                                ident,
+                                params.len(),
+                                args.len()
+                            ),
+                            span,
+                        )
+                        .with_code("E0015"));
+                    }
+                    let mut expanded_args = HashMap::new();
+                    for (param, arg) in params.iter().zip(args.iter()) {
+                        expanded_args.insert(
+                            param.as_str(),
+                            self.expand(arg.trim(), hidden, line_no, span)?,
+                        );
+                    }
+                    substitute(&mac.body, &expanded_args)
+                }
+            };
+            out += &self.expand(&body, &inner, line_no, span)?;
+        }
+        Ok(out)
+    }
+}
+
+/// Splits the arguments of a macro invocation whose `(` ends right before
+/// `start`. Returns the arguments and the position after the closing `)`.
+fn split_args(text: &str, start: usize) -> Option<(Vec<String>, usize)> {
+    let mut args = Vec::new();
+    let mut depth = 0;
+    let mut current = start;
+    for (i, c) in text[start..].char_indices() {
+        let i = start + i;
+        match c {
+            '(' => depth += 1,
+            ')' if depth == 0 => {
+                args.push(text[current..i].to_string());
+                return Some((args, i + 1));
+            }
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                args.push(text[current..i].to_string());
+                current = i + 1;
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Replaces parameter names in a macro body by the expanded arguments.
+fn substitute(body: &str, args: &HashMap<&str, String>) -> String {
+    let bytes = body.as_bytes();
+    let mut out = String::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        if is_ident_start(bytes[i]) || bytes[i].is_ascii_digit() {
+            let start = i;
+            while i < bytes.len() && is_ident_char(bytes[i]) {
+                i += 1;
+            }
+            let word = &body[start..i];
+            match args.get(word) {
+                Some(arg) if is_ident_start(bytes[start]) => out += arg,
+                _ => out += word,
+            }
+        } else {
+            let c = body[i..].chars().next().unwrap();
+            out.push(c);
+            i += c.len_utf8();
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(text: &str) -> Result<String, String> {
+        let mut source_map = SourceMap::default();
+        let mut pp = Preprocessor {
+            source_map: &mut source_map,
+            macros: HashMap::new(),
+            include_stack: Vec::new(),
+            out: String::new(),
+        };
+        pp.file(Path::new("t.c"), text).map_err(|err| err.message)?;
+        Ok(pp.out)
+    }
+
+    #[test]
+    fn macros_are_expanded() {
+        let out = run("#define N 10\n#define ADD(a, b) ((a) + (b))\nint x = ADD(N, ADD(1, 2));\n")
+            .unwrap();
+        assert!(out.contains("int x = ((10) + (((1) + (2))));"), "{}", out);
+        let out = run("#define N 1\n#undef N\nint y = N;\n").unwrap();
+        assert!(out.contains("int y = N;"), "{}", out);
+    }
+
+    #[test]
+    fn conditionals_nest() {
+        let out = run(
+            "#define A\n#ifdef A\n#ifndef B\nyes;\n#else\nno;\n#endif\n#else\nnever;\n#endif\n",
+        )
+        .unwrap();
+        assert!(out.contains("yes;"));
+        assert!(!out.contains("no;") && !out.contains("never;"), "{}", out);
+        // Lines stay where they were, so positions need no markers.
+        assert_eq!(out.lines().position(|line| line == "yes;"), Some(4));
+    }
+
+    #[test]
+    fn line_expands_to_the_current_line() {
+        let out = run("int a;\n/* one\n two */\nint l = __LINE__;\n").unwrap();
+        assert!(out.contains("int l = 4;"), "{}", out);
+        assert_eq!(
+            run("#define __LINE__ 1\n"),
+            Err("cannot redefine `__LINE__`".to_string())
+        );
+    }
+
+    #[test]
+    fn macro_arguments_span_lines() {
+        let out = run("#define ADD(a, b) ((a) + (b))\nint x = ADD(1,\n  2);\nint l = __LINE__;\n")
+            .unwrap();
+        assert!(out.contains("int x = ((1) + (2));"), "{}", out);
+        assert_eq!(out.lines().position(|line| line == "int l = 4;"), Some(4));
+        assert_eq!(
+            run("#define F(a) a\nint x = F(1,\n#define G\n"),
+            Err("unterminated argument list invoking macro `F`".to_string())
+        );
+    }
+
+    #[test]
+    fn unreadable_includes_have_a_code() {
+        let mut source_map = SourceMap::default();
+        let mut pp = Preprocessor {
+            source_map: &mut source_map,
+            macros: HashMap::new(),
+            include_stack: Vec::new(),
+            out: String::new(),
+        };
+        let err = pp
+            .file(Path::new("t.c"), "#include \"missing.h\"\n")
+            .unwrap_err();
+        assert_eq!(err.code, Some("E0018"));
+    }
+
+    #[test]
+    fn unbalanced_conditionals_are_errors() {
+        assert_eq!(
+            run("#ifdef A\n"),
+            Err("unterminated conditional directive".to_string())
+        );
+    }
+}
@@ -0,0 +1,568 @@
+//! An interactive session that compiles and runs one snippet at a time, for
+//! seeing how each SysY construct lowers. Declarations and function
+//! definitions persist; statements and expressions run as the body of `main`.
+
+use crate::ast::*;
+use crate::ast2ir::{self, compute_expr, IdElement, IdTable, Module};
+use crate::diag::{self, Diagnostic, Severity, SourceMap, Span};
+use crate::interp::Interpreter;
+use crate::ir2riscv;
+use crate::lexer::Lexer;
+use crate::runtime::Runtime;
+use crate::CompUnitParser;
+use std::collections::HashSet;
+use std::io::{self, IsTerminal};
+use std::panic::{self, AssertUnwindSafe};
+
+const HELP: &str = "\
+Type declarations, function definitions, statements or an expression.
+  :ir     show the Koopa IR of the last snippet
+  :asm    show the RISC-V assembly of the last snippet
+  :help   show this message
+  :quit   leave the session (so does the end of input)
+";
+
+enum Snippet {
+    /// Declarations and definitions, added to the session.
+    Items(Vec<CompItem>),
+    /// Statements, run as the body of `main`.
+    Stmts(FuncDef),
+    /// An expression, returned from `main` and printed.
+    Expr(FuncDef),
+}
+
+struct Session<'a> {
+    runtime: &'a Runtime,
+    source_map: &'a mut SourceMap,
+    interp: Interpreter<'static>,
+    /// Every declaration and definition so far, in the order entered.
+    items: Vec<CompItem>,
+    /// What each name in `items` and the runtime library stands for.
+    table: IdTable<'static>,
+    /// The modules of the last snippet that compiled, and what it defined.
+    last: Option<(Vec<Module>, Vec<String>)>,
+}
+
+/// Runs a session on stdin and stdout until `:quit` or the end of input.
+pub fn run(runtime: &Runtime, source_map: &mut SourceMap) -> io::Result<()> {
+    let input = Box::new(io::stdin().lock());
+    let output = Box::new(io::BufWriter::new(io::stdout()));
+    let mut session = Session::new(runtime, source_map, input, output);
+    let interactive = io::stdin().is_terminal();
+    while let Some(text) = session.read_snippet(interactive)? {
+        let text = text.trim();
+        let result = match text {
+            "" => continue,
+            ":quit" | ":q" => break,
+            ":help" => Ok(HELP.to_string()),
+            ":ir" => session.ir(),
+            ":asm" => session.asm(),
+            _ if text.starts_with(':') => Err(vec![Diagnostic::error(
+                format!("unknown command `{}`, try `:help`", text),
+                None,
+            )]),
+            _ => session
+                .eval(text)
+                .map(|value| value.map_or(String::new(), |v| format!("{}\n", v))),
+        };
+        let output = session.interp.output();
+        match result {
+            Ok(text) => {
+                output.write_all(text.as_bytes())?;
+                output.flush()?;
+            }
+            Err(errors) => {
+                output.flush()?;
+                for err in errors.iter() {
+                    eprint!("{}", session.source_map.render(err));
+                }
+            }
+        }
+    }
+    session.interp.output().flush()
+}
+
+/// A function definition of `main` with no source of its own.
+fn empty_main() -> CompItem {
+    CompItem::FuncDef(FuncDef {
+        func_type: FuncType::Int,
+        ident: "main".to_string(),
+        block: Block { items: Vec::new() },
+        params: Vec::new(),
+        span: Span::new(0, 0),
+    })
+}
+
+fn defines_main(item: &CompItem) -> bool {
+    matches!(item, CompItem::FuncDef(def) if def.ident == "main")
+}
+
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic")
+}
+
+impl<'a> Session<'a> {
+    fn new(
+        runtime: &'a Runtime,
+        source_map: &'a mut SourceMap,
+        input: Box<dyn io::BufRead>,
+        output: Box<dyn io::Write>,
+    ) -> Self {
+        let mut table = IdTable::new(None, 0);
+        for func in runtime.funcs() {
+            table.insert(
+                func.name.clone(),
+                IdElement::Func(func.kind.clone(), func.params.len()),
+            );
+        }
+        Session {
+            runtime,
+            source_map,
+            interp: Interpreter::new(&[], input, output).expect("there are no globals to evaluate"),
+            items: Vec::new(),
+            table,
+            last: None,
+        }
+    }
+}
+
+impl Session<'_> {
+    /// Reads one line, or more while braces are left open.
+    fn read_snippet(&mut self, interactive: bool) -> io::Result<Option<String>> {
+        let mut text = String::new();
+        let mut depth = 0;
+        loop {
+            if interactive {
+                let prompt = if text.is_empty() { "sysy> " } else { "  ... " };
+                let output = self.interp.output();
+                output.write_all(prompt.as_bytes())?;
+                output.flush()?;
+            }
+            let mut line = String::new();
+            if self.interp.input().read_line(&mut line)? == 0 {
+                return Ok(if text.is_empty() { None } else { Some(text) });
+            }
+            depth += line.matches('{').count() as i32 - line.matches('}').count() as i32;
+            text += &line;
+            if depth <= 0 {
+                return Ok(Some(text));
+            }
+        }
+    }
+
+    /// Parses `text` between `prefix` and `suffix`, with positions that
+    /// point into the snippet itself. Errors also tell how far into the
+    /// snippet parsing got.
+    fn parse(
+        &mut self,
+        prefix: &str,
+        text: &str,
+        suffix: &str,
+    ) -> Result<CompUnit, Box<(Diagnostic, usize)>> {
+        let head = format!("{}# 1 \"<repl>\"\n", prefix);
+        let source = format!("{}{}\n{}", head, text, suffix);
+        let base = self.source_map.add("<repl>", &source);
+        CompUnitParser::new()
+            .parse(Lexer::new(&source, base))
+            .map_err(|err| {
+                let mut err: Diagnostic = err.into();
+                let start = base + head.len();
+                let reached = err.span.map_or(0, |span| span.lo.saturating_sub(start));
+                if reached > text.len() {
+                    // The error is in the wrapper: the snippet ended early.
+                    let end = start + text.len();
+                    err.span = Some(Span::new(end, end));
+                    err.message = match err.message.split_once(", expected") {
+                        Some((_, expected)) => {
+                            format!("unexpected end of input, expected{}", expected)
+                        }
+                        None => "unexpected end of input".to_string(),
+                    };
+                }
+                Box::new((err, reached.min(text.len())))
+            })
+    }
+
+    /// Tells what kind of snippet `text` is by the first way it parses.
+    fn classify(&mut self, text: &str) -> Result<Snippet, Diagnostic> {
+        let main = |unit: CompUnit| match unit.list.into_iter().next() {
+            Some(CompItem::FuncDef(def)) => def,
+            _ => unreachable!("the wrapper is a single function"),
+        };
+        let items = match self.parse("", text, "") {
+            Ok(unit) => return Ok(Snippet::Items(unit.list)),
+            Err(err) => err,
+        };
+        let expr = match self.parse("int main() { return\n", text, ";\n}\n") {
+            Ok(unit) => {
+                let def = main(unit);
+                let void_call = match def.block.items.first() {
+                    Some(BlockItem::Stmt(Stmt::Ret(Some(Expr::Func(name, _, _))), _)) => matches!(
+                        self.table.get(name).0,
+                        Some(IdElement::Func(kind, _)) if kind == "void"
+                    ),
+                    _ => false,
+                };
+                if !void_call {
+                    return Ok(Snippet::Expr(def));
+                }
+                // A call of a `void` function is run for its effect.
+                let unit = self.parse("int main() {\n", text, ";\n}\n");
+                return Ok(Snippet::Stmts(main(unit.map_err(|err| err.0)?)));
+            }
+            Err(err) => err,
+        };
+        let stmts = match self.parse("int main() {\n", text, "}\n") {
+            Ok(unit) => return Ok(Snippet::Stmts(main(unit))),
+            Err(err) => err,
+        };
+        // Report the reading that made sense of the most input.
+        let err = [items, expr, stmts]
+            .into_iter()
+            .rev()
+            .max_by_key(|err| err.1)
+            .unwrap();
+        Err(err.0)
+    }
+
+    /// Compiles and runs a snippet. Returns the value of an expression.
+    fn eval(&mut self, text: &str) -> Result<Option<i32>, Vec<Diagnostic>> {
+        let snippet = self.classify(text).map_err(|err| vec![err])?;
+        let (new_items, wrapper) = match &snippet {
+            Snippet::Items(items) => (items.as_slice(), None),
+            Snippet::Stmts(def) | Snippet::Expr(def) => (&[][..], Some(def)),
+        };
+        let count = new_items.len();
+        // Definitions replace earlier ones of the same name, and a snippet
+        // run as `main` replaces the user's own.
+        let funcs: Vec<&str> = new_items
+            .iter()
+            .filter_map(|item| match item {
+                CompItem::FuncDef(def) => Some(def.ident.as_str()),
+                _ => None,
+            })
+            .collect();
+        let vars: Vec<String> = new_items
+            .iter()
+            .filter(|item| !matches!(item, CompItem::FuncDef(_) | CompItem::FuncDecl(_)))
+            .flat_map(CompItem::names)
+            .collect();
+        let kept: Vec<CompItem> = self
+            .items
+            .iter()
+            .filter_map(|item| match item {
+                CompItem::FuncDef(def) if funcs.contains(&def.ident.as_str()) => None,
+                CompItem::FuncDef(FuncDef { ident, .. })
+                | CompItem::FuncDecl(FuncDecl { ident, .. })
+                    if wrapper.is_some() && ident == "main" =>
+                {
+                    None
+                }
+                CompItem::ConstDecl(defs) => {
+                    let defs: Vec<ConstDef> = defs
+                        .iter()
+                        .filter(|def| !vars.contains(&def.id))
+                        .cloned()
+                        .collect();
+                    (!defs.is_empty()).then_some(CompItem::ConstDecl(defs))
+                }
+                CompItem::VarDecl(defs) | CompItem::ExternDecl(defs) => {
+                    let defs: Vec<VarDef> = defs
+                        .iter()
+                        .filter(|def| !vars.contains(&def.id))
+                        .cloned()
+                        .collect();
+                    (!defs.is_empty()).then_some(match item {
+                        CompItem::VarDecl(_) => CompItem::VarDecl(defs),
+                        _ => CompItem::ExternDecl(defs),
+                    })
+                }
+                item => Some(item.clone()),
+            })
+            .collect();
+        let mut list = kept.clone();
+        list.extend(new_items.iter().cloned());
+        match wrapper {
+            Some(def) => list.push(CompItem::FuncDef(def.clone())),
+            None if !list.iter().any(defines_main) => list.push(empty_main()),
+            None => {}
+        }
+        let mut units = [CompUnit { list }];
+        let runtime = self.runtime;
+        let source_map = &*self.source_map;
+        let modules = panic::catch_unwind(AssertUnwindSafe(|| {
+            ast2ir::ast2ir(&mut units, runtime, source_map)
+        }));
+        let mut errors = diag::take();
+        let modules = match modules {
+            Ok(modules) => modules,
+            Err(payload) => {
+                errors.push(Diagnostic::error(
+                    format!("internal compiler error: {}", panic_message(&*payload)),
+                    None,
+                ));
+                return Err(errors);
+            }
+        };
+        if errors.iter().any(|d| d.severity == Severity::Error) {
+            return Err(errors);
+        }
+        // Take back the snippet's items, which `ast2ir` may have rewritten.
+        let [CompUnit { mut list }] = units;
+        let mut new_items = list.split_off(kept.len());
+        let wrapper = match wrapper {
+            Some(_) => new_items.pop(),
+            None => {
+                // Drop the stand-in `main`, if there is one.
+                new_items.truncate(count);
+                None
+            }
+        };
+        match wrapper {
+            None => {
+                let defined = new_items.iter().flat_map(CompItem::names).collect();
+                self.last = Some((modules, defined));
+                self.items = kept;
+                for item in new_items {
+                    self.declare(&item);
+                    self.interp.global(&item).map_err(|err| vec![err])?;
+                    self.items.push(item);
+                }
+                Ok(None)
+            }
+            Some(wrapper) => {
+                self.last = Some((modules, vec!["main".to_string()]));
+                self.interp.global(&wrapper).map_err(|err| vec![err])?;
+                let value = self.interp.call("main", Vec::new(), None);
+                // Put back the user's own `main`.
+                if let Some(main) = self.items.iter().find(|item| defines_main(item)) {
+                    self.interp.global(main).map_err(|err| vec![err])?;
+                }
+                let value = value.map_err(|err| vec![err])?;
+                Ok(matches!(snippet, Snippet::Expr(_)).then_some(value))
+            }
+        }
+    }
+
+    /// Records what the names of a new item stand for.
+    fn declare(&mut self, item: &CompItem) {
+        match item {
+            CompItem::FuncDef(FuncDef {
+                ident,
+                func_type,
+                params,
+                ..
+            })
+            | CompItem::FuncDecl(FuncDecl {
+                ident,
+                func_type,
+                params,
+                ..
+            }) => {
+                let kind = match func_type {
+                    FuncType::Int => "i32",
+                    FuncType::Void => "void",
+                };
+                self.table.insert(
+                    ident.clone(),
+                    IdElement::Func(kind.to_string(), params.len()),
+                );
+            }
+            CompItem::ConstDecl(defs) => {
+                for def in defs.iter() {
+                    let value = compute_expr(&def.value, &self.table).unwrap_or_default();
+                    self.table.insert(def.id.clone(), IdElement::Const(value));
+                }
+            }
+            CompItem::VarDecl(defs) | CompItem::ExternDecl(defs) => {
+                for def in defs.iter() {
+                    self.table
+                        .insert(def.id.clone(), IdElement::Var("i32".to_string()));
+                }
+            }
+        }
+    }
+
+    fn last(&self) -> Result<&(Vec<Module>, Vec<String>), Vec<Diagnostic>> {
+        self.last
+            .as_ref()
+            .ok_or_else(|| vec![Diagnostic::error("no snippet has been compiled yet", None)])
+    }
+
+    /// The Koopa functions and globals the last snippet defined.
+    fn ir(&self) -> Result<String, Vec<Diagnostic>> {
+        let (modules, defined) = self.last()?;
+        let mut out = String::new();
+        for module in modules.iter() {
+            let mut keep = false;
+            for line in module.koopa.lines() {
+                if let Some(rest) = line.strip_prefix("fun @") {
+                    keep = defined
+                        .iter()
+                        .any(|name| rest.split('(').next() == Some(name));
+                } else if let Some(rest) = line.strip_prefix("global @") {
+                    let name = rest.split(' ').next().unwrap_or_default();
+                    if defined.iter().any(|n| global_is(name, n)) {
+                        out += line;
+                        out += "\n";
+                    }
+                    continue;
+                }
+                if keep {
+                    out += line;
+                    out += "\n";
+                    keep = line != "}";
+                }
+            }
+        }
+        Ok(out)
+    }
+
+    /// The RISC-V functions and data the last snippet defined.
+    fn asm(&self) -> Result<String, Vec<Diagnostic>> {
+        let (modules, defined) = self.last()?;
+        // Keep a back end failure from printing a panic message.
+        let hook = panic::take_hook();
+        panic::set_hook(Box::new(|_| {}));
+        let asm = panic::catch_unwind(|| {
+            let mut written = HashSet::new();
+            for module in modules.iter() {
+                written.extend(ir2riscv::written_globals(&module.koopa)?);
+            }
+            modules
+                .iter()
+                .map(|module| {
+                    ir2riscv::ir2riscv(
+                        module.koopa.clone(),
+                        &module.externs,
+                        &written,
+                        &module.symbols,
+                    )
+                })
+                .collect::<Result<String, Diagnostic>>()
+        });
+        panic::set_hook(hook);
+        let asm = asm.map_err(|payload| {
+            vec![Diagnostic::error(
+                format!(
+                    "the RISC-V back end cannot translate this snippet yet: {}",
+                    panic_message(&*payload)
+                ),
+                None,
+            )]
+        })?;
+        let asm = asm.map_err(|err| vec![err])?;
+        let mut out = String::new();
+        let mut section = "";
+        let mut keep = false;
+        for line in asm.lines() {
+            if line == ".text" || line == ".data" || line.starts_with(".section") {
+                section = line;
+                keep = false;
+                continue;
+            }
+            if let Some(name) = line.strip_prefix(".globl ") {
+                keep = defined.iter().any(|n| match section {
+                    ".text" => name == n,
+                    _ => global_is(name, n),
+                });
+                if keep {
+                    out += section;
+                    out += "\n";
+                }
+            }
+            if keep {
+                out += line;
+                out += "\n";
+            }
+        }
+        Ok(out)
+    }
+}
+
+/// Whether a global's Koopa name, which carries a scope suffix such as
+/// `x_0`, is that of the SysY variable `name`.
+fn global_is(koopa_name: &str, name: &str) -> bool {
+    koopa_name
+        .rsplit_once('_')
+        .is_some_and(|(base, scope)| base == name && scope.parse::<u32>().is_ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_session(f: impl FnOnce(&mut Session)) {
+        let runtime = Runtime::default();
+        let mut source_map = SourceMap::default();
+        let mut session = Session::new(
+            &runtime,
+            &mut source_map,
+            Box::new(io::empty()),
+            Box::new(io::sink()),
+        );
+        f(&mut session);
+    }
+
+    #[test]
+    fn snippets_are_classified_by_the_first_reading_that_parses() {
+        with_session(|session| {
+            let kind = |session: &mut Session, text| match session.classify(text) {
+                Ok(Snippet::Items(_)) => "items",
+                Ok(Snippet::Stmts(_)) => "stmts",
+                Ok(Snippet::Expr(_)) => "expr",
+                Err(_) => "error",
+            };
+            assert_eq!(kind(session, "int x = 1;"), "items");
+            assert_eq!(kind(session, "int f() { return 1; }"), "items");
+            assert_eq!(kind(session, "1 + 2"), "expr");
+            assert_eq!(kind(session, "getint()"), "expr");
+            // A call of a `void` function has no value to print.
+            assert_eq!(kind(session, "putint(1)"), "stmts");
+            assert_eq!(kind(session, "x = 2; putint(x);"), "stmts");
+            assert_eq!(kind(session, "if (1) {"), "error");
+        });
+    }
+
+    #[test]
+    fn parse_errors_come_from_the_reading_that_got_furthest() {
+        with_session(|session| {
+            let Err(err) = session.classify("x = 1 +;") else {
+                panic!("`x = 1 +;` parsed")
+            };
+            assert!(err.message.contains("`;`"), "{}", err.message);
+            let Err(err) = session.classify("1 +") else {
+                panic!("`1 +` parsed")
+            };
+            assert!(
+                err.message.starts_with("unexpected end of input"),
+                "{}",
+                err.message
+            );
+        });
+    }
+
+    /// Runs `text`, with the messages of the errors it causes.
+    fn eval(session: &mut Session, text: &str) -> Result<Option<i32>, Vec<String>> {
+        session
+            .eval(text)
+            .map_err(|errors| errors.into_iter().map(|d| d.message).collect())
+    }
+
+    #[test]
+    fn definitions_persist_between_snippets() {
+        with_session(|session| {
+            assert_eq!(eval(session, "int x = 3;"), Ok(None));
+            assert_eq!(eval(session, "int f(int a) { return a * x; }"), Ok(None));
+            assert_eq!(eval(session, "x = 4;"), Ok(None));
+            assert_eq!(eval(session, "f(2)"), Ok(Some(8)));
+            // A new definition replaces the old one.
+            assert_eq!(eval(session, "int f(int a) { return a + x; }"), Ok(None));
+            assert_eq!(eval(session, "f(2)"), Ok(Some(6)));
+        });
+    }
+}
@@ -0,0 +1,140 @@
+use crate::diag::{Diagnostic, SourceMap, Span};
+use std::fs::read_to_string;
+
+/// The SysY runtime library, in the same format as user spec files: one
+/// function per line, written like a Koopa `decl` without `decl @`.
+const SYLIB_SPEC: &str = "getint(): i32
+getch(): i32
+getarray(*i32): i32
+putint(i32)
+putch(i32)
+putarray(i32, *i32)
+_sysy_starttime(i32)
+_sysy_stoptime(i32)
+";
+
+/// `sylib.h` defines these as macros passing `__LINE__` to the real timing
+/// functions. Calls are lowered the same way when generating IR.
+pub const TIMING_FUNCS: [(&str, &str); 2] = [
+    ("starttime", "_sysy_starttime"),
+    ("stoptime", "_sysy_stoptime"),
+];
+
+pub struct RuntimeFunc {
+    pub name: String,
+    /// Koopa types of the parameters.
+    pub params: Vec<String>,
+    /// `i32` or `void`, as in `IdElement::Func`.
+    pub kind: String,
+}
+
+impl RuntimeFunc {
+    pub fn decl(&self) -> String {
+        let mut out = format!("decl @{}({})", self.name, self.params.join(", "));
+        if self.kind != "void" {
+            out += &format!(": {}", self.kind);
+        }
+        out + "\n"
+    }
+}
+
+/// The external functions every program can call without declaring them:
+/// the SysY runtime plus whatever user spec files add.
+pub struct Runtime {
+    funcs: Vec<RuntimeFunc>,
+}
+
+impl Default for Runtime {
+    fn default() -> Self {
+        let mut runtime = Runtime { funcs: Vec::new() };
+        runtime.parse(SYLIB_SPEC, 0).unwrap();
+        runtime
+    }
+}
+
+impl Runtime {
+    pub fn funcs(&self) -> &[RuntimeFunc] {
+        &self.funcs
+    }
+
+    pub fn get(&self, name: &str) -> Option<&RuntimeFunc> {
+        self.funcs.iter().find(|f| f.name == name)
+    }
+
+    /// The Koopa `decl`s of every function.
+    pub fn decls(&self) -> String {
+        self.funcs.iter().map(RuntimeFunc::decl).collect()
+    }
+
+    /// Adds the functions of a user spec file.
+    pub fn load(&mut self, path: &str, source_map: &mut SourceMap) -> Result<(), Diagnostic> {
+        let text = read_to_string(path)
+            .map_err(|e| Diagnostic::error(format!("cannot read `{}`: {}", path, e), None))?;
+        let base = source_map.add(path, &text);
+        self.parse(&text, base)
+    }
+
+    fn parse(&mut self, text: &str, base: usize) -> Result<(), Diagnostic> {
+        let mut offset = base;
+        for raw in text.split_inclusive('\n') {
+            let line = raw.split("//").next().unwrap().trim_end();
+            let indent = line.len() - line.trim_start().len();
+            let span = Some(Span::new(offset + indent, offset + line.len()));
+            offset += raw.len();
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let malformed = || {
+                Diagnostic::error("malformed runtime function", span)
+                    .with_note("expected `name(type, ...)` or `name(type, ...): i32`")
+            };
+            let (name, rest) = line.split_once('(').ok_or_else(malformed)?;
+            let (params, kind) = rest.split_once(')').ok_or_else(malformed)?;
+            let name = name.trim();
+            if name.is_empty()
+                || name.starts_with(|c: char| c.is_ascii_digit())
+                || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+            {
+                return Err(malformed());
+            }
+            let params: Vec<String> = match params.trim() {
+                "" => Vec::new(),
+                params => params.split(',').map(|p| p.trim().to_string()).collect(),
+            };
+            for param in params.iter() {
+                if param.trim_start_matches('*') != "i32" {
+                    return Err(Diagnostic::error(
+                        format!("unsupported parameter type `{}`", param),
+                        span,
+                    )
+                    .with_note("parameters must be `i32` or pointers to it"));
+                }
+            }
+            let kind = match kind.trim().strip_prefix(':').map(str::trim) {
+                None if kind.trim().is_empty() => "void".to_string(),
+                Some("i32") => "i32".to_string(),
+                None => return Err(malformed()),
+                Some(other) => {
+                    return Err(Diagnostic::error(
+                        format!("unsupported return type `{}`", other),
+                        span,
+                    )
+                    .with_note("functions either return `i32` or nothing"))
+                }
+            };
+            if self.get(name).is_some() {
+                return Err(Diagnostic::error(
+                    format!("runtime function `{}` is declared twice", name),
+                    span,
+                ));
+            }
+            self.funcs.push(RuntimeFunc {
+                name: name.to_string(),
+                params,
+                kind,
+            });
+        }
+        Ok(())
+    }
+}
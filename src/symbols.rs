@@ -0,0 +1,412 @@
+use crate::ast::*;
+use crate::ast2ir::{compute_expr, IdElement, IdTable};
+use crate::diag::Span;
+use crate::runtime::{Runtime, TIMING_FUNCS};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolKind {
+    Function,
+    Variable,
+    Parameter,
+    Constant,
+}
+
+#[derive(Debug)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: SymbolKind,
+    /// Where the name is declared; `None` for runtime library functions.
+    pub span: Option<Span>,
+    /// The declaration as SysY source, e.g. `const int N = 10`.
+    pub detail: String,
+    /// The function a local or parameter belongs to.
+    pub parent: Option<usize>,
+}
+
+/// Every declaration in a compilation unit and every use of a name resolved
+/// to the declaration it refers to, for editor tooling.
+#[derive(Debug, Default)]
+pub struct Index {
+    pub symbols: Vec<Symbol>,
+    pub references: Vec<(Span, usize)>,
+}
+
+impl Index {
+    /// The symbol declared or used at a span accepted by `hit`.
+    pub fn find(&self, hit: impl Fn(Span) -> bool) -> Option<usize> {
+        self.symbols
+            .iter()
+            .position(|s| s.span.is_some_and(&hit))
+            .or_else(|| {
+                self.references
+                    .iter()
+                    .find(|(span, _)| hit(*span))
+                    .map(|(_, symbol)| *symbol)
+            })
+    }
+
+    pub fn references_to(&self, symbol: usize) -> impl Iterator<Item = Span> + '_ {
+        self.references
+            .iter()
+            .filter(move |(_, s)| *s == symbol)
+            .map(|(span, _)| *span)
+    }
+}
+
+/// Resolves names the way `ast2ir` does, with the same `IdTable` scoping.
+struct Indexer {
+    index: Index,
+    /// Symbols by the scoped name `IdTable` gives them.
+    keys: HashMap<String, usize>,
+    offset: i32,
+    function: Option<usize>,
+    /// Functions seen only as prototypes so far.
+    prototypes: Vec<usize>,
+}
+
+/// Builds the index of a unit as written, before `ast2ir` lowers its
+/// `starttime()` and `stoptime()` calls. Constant values are computed, so
+/// callers should discard the diagnostics this records.
+pub fn index(unit: &CompUnit, runtime: &Runtime) -> Index {
+    let mut indexer = Indexer {
+        index: Index::default(),
+        keys: HashMap::new(),
+        offset: 0,
+        function: None,
+        prototypes: Vec::new(),
+    };
+    let mut globals = IdTable::new(None, 0);
+    for func in runtime.funcs() {
+        let params: Vec<&str> = func
+            .params
+            .iter()
+            .map(|p| if p.starts_with('*') { "int[]" } else { "int" })
+            .collect();
+        let detail = format!(
+            "{} {}({})",
+            kind_name(&func.kind),
+            func.name,
+            params.join(", ")
+        );
+        indexer.define(
+            &mut globals,
+            &func.name,
+            IdElement::Func(func.kind.clone(), func.params.len()),
+            SymbolKind::Function,
+            None,
+            detail,
+        );
+    }
+    // The `sylib.h` macros `ast2ir` expands, unless the unit has its own.
+    let declared: Vec<String> = unit.list.iter().flat_map(CompItem::names).collect();
+    for (name, _) in TIMING_FUNCS {
+        if !declared.iter().any(|d| d == name) {
+            indexer.define(
+                &mut globals,
+                name,
+                IdElement::Func("void".to_string(), 0),
+                SymbolKind::Function,
+                None,
+                format!("void {}()", name),
+            );
+        }
+    }
+    for item in unit.list.iter() {
+        match item {
+            CompItem::FuncDecl(decl) => {
+                indexer.function_decl(
+                    &mut globals,
+                    &decl.ident,
+                    &decl.func_type,
+                    &decl.params,
+                    decl.span,
+                    false,
+                );
+            }
+            CompItem::FuncDef(def) => {
+                indexer.function_decl(
+                    &mut globals,
+                    &def.ident,
+                    &def.func_type,
+                    &def.params,
+                    def.span,
+                    true,
+                );
+                indexer.function(&globals, def);
+            }
+            CompItem::ConstDecl(defs) => indexer.consts(&mut globals, defs),
+            CompItem::VarDecl(defs) => indexer.vars(&mut globals, defs, "int"),
+            CompItem::ExternDecl(defs) => indexer.vars(&mut globals, defs, "extern int"),
+        }
+    }
+    indexer.index
+}
+
+fn kind_name(kind: &str) -> &'static str {
+    match kind {
+        "void" => "void",
+        _ => "int",
+    }
+}
+
+impl Indexer {
+    fn define(
+        &mut self,
+        table: &mut IdTable,
+        name: &str,
+        element: IdElement,
+        kind: SymbolKind,
+        span: Option<Span>,
+        detail: String,
+    ) -> usize {
+        table.insert(name.to_string(), element);
+        self.index.symbols.push(Symbol {
+            name: name.to_string(),
+            kind,
+            span,
+            detail,
+            parent: self.function,
+        });
+        let symbol = self.index.symbols.len() - 1;
+        self.keys.insert(table.get(&name.to_string()).1, symbol);
+        symbol
+    }
+
+    fn reference(&mut self, table: &IdTable, name: &str, span: Span) {
+        if let Some(symbol) = self.keys.get(&table.get(&name.to_string()).1) {
+            self.index.references.push((span, *symbol));
+        }
+    }
+
+    /// Declares a function. Prototypes and the definition share a symbol,
+    /// which is placed at the definition once there is one.
+    fn function_decl(
+        &mut self,
+        table: &mut IdTable,
+        name: &str,
+        func_type: &FuncType,
+        params: &[FuncParam],
+        span: Span,
+        defined: bool,
+    ) {
+        let kind = match func_type {
+            FuncType::Int => "i32",
+            FuncType::Void => "void",
+        };
+        let params: Vec<String> = params.iter().map(|p| format!("int {}", p.ident)).collect();
+        let detail = format!("{} {}({})", kind_name(kind), name, params.join(", "));
+        let previous = match table.get(&name.to_string()) {
+            (Some(IdElement::Func(..)), key) => self.keys.get(&key).copied(),
+            _ => None,
+        };
+        match previous {
+            Some(symbol) if self.prototypes.contains(&symbol) => {
+                if defined {
+                    let symbol_ref = &mut self.index.symbols[symbol];
+                    let prototype = symbol_ref.span.replace(span);
+                    symbol_ref.detail = detail;
+                    self.index.references.extend(prototype.map(|p| (p, symbol)));
+                    self.prototypes.retain(|p| *p != symbol);
+                } else {
+                    self.index.references.push((span, symbol));
+                }
+            }
+            _ => {
+                let symbol = self.define(
+                    table,
+                    name,
+                    IdElement::Func(kind.to_string(), params.len()),
+                    SymbolKind::Function,
+                    Some(span),
+                    detail,
+                );
+                if !defined {
+                    self.prototypes.push(symbol);
+                }
+            }
+        }
+    }
+
+    fn function(&mut self, globals: &IdTable, def: &FuncDef) {
+        self.offset += 1;
+        let mut table = IdTable::new(Some(globals), self.offset);
+        self.function = self.keys.get(&globals.get(&def.ident).1).copied();
+        for param in def.params.iter() {
+            self.define(
+                &mut table,
+                &param.ident,
+                IdElement::Var("i32".to_string()),
+                SymbolKind::Parameter,
+                Some(param.span),
+                format!("int {}", param.ident),
+            );
+        }
+        self.block(&mut table, &def.block);
+        self.function = None;
+    }
+
+    fn consts(&mut self, table: &mut IdTable, defs: &[ConstDef]) {
+        for def in defs.iter() {
+            self.expr(table, &def.value);
+            let (element, detail) = match compute_expr(&def.value, table) {
+                Ok(value) => (
+                    IdElement::Const(value),
+                    format!("const int {} = {}", def.id, value),
+                ),
+                Err(_) => (IdElement::Const(0), format!("const int {}", def.id)),
+            };
+            self.define(
+                table,
+                &def.id,
+                element,
+                SymbolKind::Constant,
+                Some(def.span),
+                detail,
+            );
+        }
+    }
+
+    fn vars(&mut self, table: &mut IdTable, defs: &[VarDef], prefix: &str) {
+        for def in defs.iter() {
+            if let Some(value) = &def.value {
+                self.expr(table, value);
+            }
+            self.define(
+                table,
+                &def.id,
+                IdElement::Var("i32".to_string()),
+                SymbolKind::Variable,
+                Some(def.span),
+                format!("{} {}", prefix, def.id),
+            );
+        }
+    }
+
+    fn block(&mut self, table: &mut IdTable, block: &Block) {
+        for item in block.items.iter() {
+            match item {
+                BlockItem::ConstDecl(defs) => self.consts(table, defs),
+                BlockItem::VarDecl(defs) => self.vars(table, defs, "int"),
+                BlockItem::Stmt(stmt, _) => self.stmt(table, stmt),
+            }
+        }
+    }
+
+    fn stmt(&mut self, table: &mut IdTable, stmt: &Stmt) {
+        match stmt {
+            Stmt::Ret(e) | Stmt::Expr(e) => {
+                if let Some(e) = e {
+                    self.expr(table, e);
+                }
+            }
+            Stmt::Assign(name, e, span) => {
+                self.reference(table, name, *span);
+                self.expr(table, e);
+            }
+            Stmt::Block(block) => {
+                self.offset += 1;
+                let mut inner = IdTable::new(Some(table), self.offset);
+                self.block(&mut inner, block);
+            }
+            Stmt::IfElse(cond, then, otherwise) => {
+                self.expr(table, cond);
+                self.stmt(table, &then.0);
+                if let Some(otherwise) = otherwise {
+                    self.stmt(table, &otherwise.0);
+                }
+            }
+            Stmt::While(cond, body) => {
+                self.expr(table, cond);
+                self.stmt(table, &body.0);
+            }
+            Stmt::Break(_) | Stmt::Continue(_) => {}
+        }
+    }
+
+    fn expr(&mut self, table: &IdTable, expr: &Expr) {
+        match expr {
+            Expr::Number(_) => {}
+            Expr::UnaryExpr(_, e) => self.expr(table, e),
+            Expr::BinaryExpr(lhs, _, rhs, _) => {
+                self.expr(table, lhs);
+                self.expr(table, rhs);
+            }
+            Expr::LVal(name, span) => self.reference(table, name, *span),
+            Expr::Func(name, args, span) => {
+                self.reference(table, name, *span);
+                for arg in args.iter() {
+                    self.expr(table, arg);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+
+    const SRC: &str = "int x = 1;
+int f(int x) {
+    int y = x;
+    {
+        const int x = 2;
+        y = y + x;
+    }
+    return x + y;
+}
+int main() { return f(x) + getint(); }
+";
+
+    fn index_src() -> Index {
+        let unit = crate::sysy::CompUnitParser::new()
+            .parse(Lexer::new(SRC, 0))
+            .unwrap();
+        index(&unit, &Runtime::default())
+    }
+
+    /// The symbol at the `nth` occurrence of `name` in `SRC`.
+    fn at<'a>(index: &'a Index, name: &str, nth: usize) -> &'a Symbol {
+        let lo = SRC.match_indices(name).nth(nth).unwrap().0;
+        let symbol = index.find(|span| span.lo <= lo && lo < span.hi).unwrap();
+        &index.symbols[symbol]
+    }
+
+    #[test]
+    fn uses_resolve_to_the_innermost_declaration() {
+        let index = index_src();
+        // `int x` global, parameter `x`, `= x` in the body.
+        assert_eq!(at(&index, "x", 2).kind, SymbolKind::Parameter);
+        // `y + x` inside the block sees the constant.
+        let constant = at(&index, "x", 4);
+        assert_eq!(constant.kind, SymbolKind::Constant);
+        assert_eq!(constant.detail, "const int x = 2");
+        // `return x + y` is back in the function scope.
+        assert_eq!(at(&index, "x", 5).kind, SymbolKind::Parameter);
+        // `f(x)` in main sees the global.
+        let global = at(&index, "x", 6);
+        assert_eq!(global.kind, SymbolKind::Variable);
+        assert_eq!(global.parent, None);
+    }
+
+    #[test]
+    fn references_cover_every_use() {
+        let index = index_src();
+        let y = index.find(|span| &SRC[span.lo..span.hi] == "y").unwrap();
+        assert_eq!(index.symbols[y].kind, SymbolKind::Variable);
+        assert_eq!(index.references_to(y).count(), 3);
+        let f = at(&index, "f", 1);
+        assert_eq!(f.kind, SymbolKind::Function);
+        assert_eq!(f.parent, None);
+    }
+
+    #[test]
+    fn runtime_functions_have_no_span() {
+        let index = index_src();
+        let getint = at(&index, "getint", 0);
+        assert_eq!(getint.kind, SymbolKind::Function);
+        assert_eq!(getint.span, None);
+    }
+}